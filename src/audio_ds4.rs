@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::common_output::calculate_checksum_bt;
+
+// SBC data carried by one 0x14 or 0x15 report, 0x17 report carries two chunks
+pub const AUDIO_CHUNK: usize = 224;
+// Output data, which 0x15 report carries before audio, laid out as in 0x11 report
+pub const AUDIO_OUTPUT_LEN: usize = 75;
+pub const PACKET_LEN_AUDIO: usize = 270;
+pub const PACKET_LEN_AUDIO_OUTPUT: usize = 334;
+pub const PACKET_LEN_AUDIO_DOUBLE: usize = 462;
+// Do not buffer more than about a second of audio, if client sends it too fast
+const MAX_BUFFERED: usize = AUDIO_CHUNK * 64;
+const SBC_SYNCWORD: u8 = 0x9C;
+// Byte after frame number, as used by old-servers/play.py
+const AUDIO_HEADER: u8 = 0x24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChannelMode {
+    Mono,
+    DualChannel,
+    Stereo,
+    JointStereo,
+}
+
+// Header of SBC frame, see https://tools.ietf.org/html/draft-ietf-avt-rtp-sbc-01#section-6.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SbcHeader {
+    sampling_frequency: u32,
+    blocks: u32,
+    channel_mode: ChannelMode,
+    subbands: u32,
+    bitpool: u32,
+}
+
+impl SbcHeader {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [SBC_SYNCWORD, info, bitpool, ..] => Some(Self {
+                sampling_frequency: match info >> 6 {
+                    0 => 16000,
+                    1 => 32000,
+                    2 => 44100,
+                    _ => 48000,
+                },
+                blocks: match (info >> 4) & 0x03 {
+                    0 => 4,
+                    1 => 8,
+                    2 => 12,
+                    _ => 16,
+                },
+                channel_mode: match (info >> 2) & 0x03 {
+                    0 => ChannelMode::Mono,
+                    1 => ChannelMode::DualChannel,
+                    2 => ChannelMode::Stereo,
+                    _ => ChannelMode::JointStereo,
+                },
+                subbands: if info & 0x01 == 0x01 { 8 } else { 4 },
+                bitpool: *bitpool as u32,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn frame_length(&self) -> usize {
+        let channels = if self.channel_mode == ChannelMode::Mono {
+            1
+        } else {
+            2
+        };
+        let bits = match self.channel_mode {
+            ChannelMode::Mono | ChannelMode::DualChannel => self.blocks * channels * self.bitpool,
+            ChannelMode::Stereo => self.blocks * self.bitpool,
+            ChannelMode::JointStereo => self.subbands + self.blocks * self.bitpool,
+        };
+        (4 + (4 * self.subbands * channels) / 8 + bits.div_ceil(8)) as usize
+    }
+
+    // Time to play one frame
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(
+            (self.blocks * self.subbands) as u64 * 1_000_000 / self.sampling_frequency as u64,
+        )
+    }
+}
+
+// SBC stream from client, which is cut into 0x14 reports, or 0x15 ones, if output
+// should be written too, or 0x17 ones, if there is enough data buffered
+#[derive(Debug, Default)]
+pub struct AudioStream {
+    buffer: VecDeque<u8>,
+    frame_number: u16,
+}
+
+impl AudioStream {
+    // Returns false, if data was dropped because buffer is full
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if self.buffer.len() + data.len() > MAX_BUFFERED {
+            return false;
+        }
+        self.buffer.extend(data);
+        true
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.buffer.len() >= AUDIO_CHUNK
+    }
+
+    // Next report and time to play it, output of AUDIO_OUTPUT_LEN bytes goes with audio,
+    // if given. Report carries whole SBC frames, the rest is left for next one.
+    // Stream which does not start with SBC frame fitting into report is dropped.
+    pub fn next_report(&mut self, output: Option<&[u8]>) -> Option<(Vec<u8>, Duration)> {
+        if !self.is_ready() {
+            return None;
+        }
+        let head: Vec<u8> = self.buffer.iter().take(3).copied().collect();
+        let header = match SbcHeader::parse(&head) {
+            Some(header) if header.frame_length() <= AUDIO_CHUNK => header,
+            _ => {
+                self.buffer.clear();
+                return None;
+            }
+        };
+        let (mut pkt, len, capacity, increment) = match output {
+            Some(output) => {
+                let mut pkt = vec![0x15, 0xC0, 0xA0];
+                pkt.extend_from_slice(output);
+                (pkt, PACKET_LEN_AUDIO_OUTPUT, AUDIO_CHUNK, 2)
+            }
+            None if self.buffer.len() >= 2 * AUDIO_CHUNK => (
+                vec![0x17, 0x40, 0xA0],
+                PACKET_LEN_AUDIO_DOUBLE,
+                2 * AUDIO_CHUNK,
+                4,
+            ),
+            None => (vec![0x14, 0x40, 0xA0], PACKET_LEN_AUDIO, AUDIO_CHUNK, 2),
+        };
+        let frames = capacity / header.frame_length();
+        pkt.extend_from_slice(&self.frame_number.to_le_bytes());
+        pkt.push(AUDIO_HEADER);
+        pkt.extend(self.buffer.drain(..frames * header.frame_length()));
+        pkt.resize(len - 4, 0);
+        let crc = calculate_checksum_bt(&pkt);
+        pkt.extend_from_slice(&crc);
+        self.frame_number = self.frame_number.wrapping_add(increment);
+        Some((pkt, header.duration() * frames as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32kHz, 16 blocks, stereo, 8 subbands, bitpool 22, so frame is 56 bytes long
+    fn sbc_frame() -> Vec<u8> {
+        let mut frame = vec![SBC_SYNCWORD, 0x79, 22];
+        frame.extend((3..56).map(|i: u32| (i * 7) as u8));
+        frame
+    }
+
+    #[test]
+    fn sbc_header() {
+        let header = SbcHeader::parse(&sbc_frame()).unwrap();
+        assert_eq!(header.frame_length(), 56);
+        assert_eq!(header.duration(), Duration::from_millis(4));
+        assert_eq!(SbcHeader::parse(&[0x00, 0x79, 22]), None);
+    }
+
+    // CRCs are taken from _14_report of old-servers/play.py
+    #[test]
+    fn audio_reports() {
+        let mut stream = AudioStream::default();
+        for _ in 0..4 {
+            assert!(stream.push(&sbc_frame()));
+        }
+        let (pkt, duration) = stream.next_report(None).unwrap();
+        assert_eq!(duration, Duration::from_millis(16));
+        assert_eq!(pkt.len(), PACKET_LEN_AUDIO);
+        assert_eq!(pkt[0..6], [0x14, 0x40, 0xA0, 0x00, 0x00, 0x24]);
+        assert_eq!(pkt[6..62], sbc_frame()[..]);
+        assert!(pkt[230..266].iter().all(|&b| b == 0));
+        assert_eq!(pkt[266..270], [0x07, 0xF0, 0xAD, 0x8F]);
+        for _ in 0..4 {
+            assert!(stream.push(&sbc_frame()));
+        }
+        let (pkt, _) = stream.next_report(None).unwrap();
+        assert_eq!(pkt[3..5], [0x02, 0x00]);
+        assert_eq!(pkt[266..270], [0xEB, 0x6C, 0x43, 0x73]);
+        assert!(stream.next_report(None).is_none());
+    }
+
+    // CRCs are taken from _15_report and _17_report of old-servers/play.py,
+    // with output of DS4 after rumble, color and volume are set
+    #[test]
+    fn output_and_double_reports() {
+        let mut output = vec![0xF7, 0, 0, 100, 200, 255, 128, 0, 0, 0];
+        output.extend_from_slice(&[0; 8]);
+        output.extend_from_slice(&[40, 40, 0x49, 80, 0x85]);
+        output.resize(AUDIO_OUTPUT_LEN, 0);
+        let mut stream = AudioStream::default();
+        for _ in 0..4 {
+            stream.push(&sbc_frame());
+        }
+        let (pkt, duration) = stream.next_report(Some(&output)).unwrap();
+        assert_eq!(duration, Duration::from_millis(16));
+        assert_eq!(pkt.len(), PACKET_LEN_AUDIO_OUTPUT);
+        assert_eq!(pkt[0..3], [0x15, 0xC0, 0xA0]);
+        assert_eq!(pkt[3..78], output[..]);
+        assert_eq!(pkt[78..81], [0x00, 0x00, 0x24]);
+        assert_eq!(pkt[81..137], sbc_frame()[..]);
+        assert_eq!(pkt[330..334], [0x2C, 0xB2, 0xFD, 0x4A]);
+        for _ in 0..8 {
+            stream.push(&sbc_frame());
+        }
+        let (pkt, duration) = stream.next_report(None).unwrap();
+        assert_eq!(duration, Duration::from_millis(32));
+        assert_eq!(pkt.len(), PACKET_LEN_AUDIO_DOUBLE);
+        assert_eq!(pkt[0..6], [0x17, 0x40, 0xA0, 0x02, 0x00, 0x24]);
+        assert_eq!(pkt[398..454], sbc_frame()[..]);
+        assert_eq!(pkt[458..462], [0xAC, 0x6B, 0x47, 0x57]);
+        assert!(!stream.is_ready());
+        assert_eq!(stream.frame_number, 6);
+    }
+
+    // Frames of 60 bytes do not fill 224 bytes, so they are not split between reports
+    #[test]
+    fn frames_are_not_split() {
+        let mut frame = vec![SBC_SYNCWORD, 0x79, 24];
+        frame.resize(60, 0x55);
+        let mut stream = AudioStream::default();
+        for _ in 0..4 {
+            stream.push(&frame);
+        }
+        let (pkt, duration) = stream.next_report(None).unwrap();
+        assert_eq!(duration, Duration::from_millis(12));
+        assert_eq!(pkt[6..186], frame.repeat(3)[..]);
+        assert!(pkt[186..266].iter().all(|&b| b == 0));
+        assert!(!stream.is_ready());
+        for _ in 0..3 {
+            stream.push(&frame);
+        }
+        let (pkt, _) = stream.next_report(None).unwrap();
+        assert_eq!(pkt[6..186], frame.repeat(3)[..]);
+    }
+
+    #[test]
+    fn misaligned_stream_is_dropped() {
+        let mut stream = AudioStream::default();
+        stream.push(&sbc_frame()[1..]);
+        for _ in 0..4 {
+            stream.push(&sbc_frame());
+        }
+        assert!(stream.next_report(None).is_none());
+        assert!(!stream.is_ready());
+    }
+}
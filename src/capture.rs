@@ -0,0 +1,393 @@
+// Recording of reports, which controller sends and gets, to capture file.
+// Capture file is text, so it could be read and attached to bug reports as is.
+// Every session of controller (from client connecting to it until disconnect)
+// is appended to the file and starts with header: version, controller type and sysname:
+//   # ds4net capture 1 dualsense-bt hidraw3
+// followed by line per report: time since start of session in microseconds,
+// I for input report as read from controller or O for output report written to it,
+// and whole report in hex:
+//   4012 I 31 10 80 7F 81 ...
+//   4230 O 31 00 10 0F 55 ...
+// When file reaches size limit or session reaches time limit, recording of session
+// stops with line telling the reason:
+//   # stopped: size limit
+// Session is not recorded at all, if file has no room for its header.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::config::{self, RecordConfig};
+use crate::hidraw::{DeviceReader, DeviceWriter};
+use crate::udevmon::DSType;
+
+const CAPTURE_MAGIC: &str = "# ds4net capture";
+const CAPTURE_VERSION: u32 = 1;
+// Room kept in file for line telling why recording stopped, both reasons are of same length
+const STOP_LINE_MAX: u64 = "# stopped: size limit\n".len() as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl Direction {
+    fn tag(self) -> char {
+        match self {
+            Direction::Input => 'I',
+            Direction::Output => 'O',
+        }
+    }
+}
+
+pub fn format_hex(report: &[u8]) -> String {
+    report
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: Duration,
+    pub direction: Direction,
+    pub report: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub ds_type: DSType,
+    pub sysname: String,
+    pub records: Vec<Record>,
+}
+
+fn invalid_data(line: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, msg),
+    )
+}
+
+// Sysname is the rest of header, as names of virtual gamepads could have spaces
+fn parse_header(header: &str, line: usize) -> io::Result<Session> {
+    let fields: Vec<&str> = header.trim().splitn(3, ' ').collect();
+    let [version, ds_type, sysname] = fields[..] else {
+        return Err(invalid_data(line, "bad header"));
+    };
+    if version.parse() != Ok(CAPTURE_VERSION) {
+        return Err(invalid_data(line, "unsupported version"));
+    }
+    let ds_type = config::type_by_name(ds_type)
+        .ok_or_else(|| invalid_data(line, "unknown controller type"))?;
+    Ok(Session {
+        ds_type,
+        sysname: sysname.to_string(),
+        records: Vec::new(),
+    })
+}
+
+fn parse_record(record: &str, line: usize) -> io::Result<Record> {
+    let mut fields = record.split_whitespace();
+    let time = fields
+        .next()
+        .and_then(|time| time.parse().ok())
+        .ok_or_else(|| invalid_data(line, "bad time"))?;
+    let direction = match fields.next() {
+        Some("I") => Direction::Input,
+        Some("O") => Direction::Output,
+        _ => return Err(invalid_data(line, "bad direction")),
+    };
+    let report = fields
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid_data(line, "bad report"))?;
+    Ok(Record {
+        time: Duration::from_micros(time),
+        direction,
+        report,
+    })
+}
+
+// Sessions of capture file, in the order they were recorded
+pub fn parse_sessions(text: &str) -> io::Result<Vec<Session>> {
+    let mut sessions: Vec<Session> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if let Some(header) = line.strip_prefix(CAPTURE_MAGIC) {
+            sessions.push(parse_header(header, i + 1)?);
+        } else if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        } else {
+            let session = sessions
+                .last_mut()
+                .ok_or_else(|| invalid_data(i + 1, "report before header"))?;
+            session.records.push(parse_record(line, i + 1)?);
+        }
+    }
+    Ok(sessions)
+}
+
+fn format_record(time: Duration, direction: Direction, report: &[u8]) -> String {
+    format!(
+        "{} {} {}\n",
+        time.as_micros(),
+        direction.tag(),
+        format_hex(report)
+    )
+}
+
+struct Capture<W: Write> {
+    file: W,
+    start: Instant,
+    size: u64,
+    max_size: u64,
+    max_duration: Duration,
+    stopped: bool,
+}
+
+impl<W: Write> Capture<W> {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn stop(&mut self, reason: &str) {
+        self.stopped = true;
+        match self.write_line(&format!("# stopped: {}\n", reason)) {
+            Ok(()) => info!("Recording stopped: {}", reason),
+            Err(e) => warn!("Error on writing capture file, recording stopped: {}", e),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, report: &[u8]) {
+        if self.stopped {
+            return;
+        }
+        let time = self.start.elapsed();
+        if time > self.max_duration {
+            return self.stop("time limit");
+        }
+        let line = format_record(time, direction, report);
+        if self.size + line.len() as u64 + STOP_LINE_MAX > self.max_size {
+            return self.stop("size limit");
+        }
+        if let Err(e) = self.write_line(&line) {
+            warn!("Error on writing capture file, recording stopped: {}", e);
+            self.stopped = true;
+        }
+    }
+}
+
+type SharedCapture = Arc<Mutex<Capture<File>>>;
+
+struct RecordingReader {
+    inner: DeviceReader,
+    capture: SharedCapture,
+}
+
+impl Read for RecordingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.capture.lock().record(Direction::Input, &buf[..count]);
+        Ok(count)
+    }
+}
+
+struct RecordingWriter {
+    inner: DeviceWriter,
+    capture: SharedCapture,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.capture.lock().record(Direction::Output, &buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn open_capture(path: &Path, record: &RecordConfig) -> io::Result<Capture<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Capture {
+        file,
+        start: Instant::now(),
+        size,
+        max_size: record.max_size,
+        max_duration: record.max_duration(),
+        stopped: false,
+    })
+}
+
+// Wraps streams of controller, so everything going through them is recorded,
+// streams are returned as they are, if capture file could not be opened
+pub fn record(
+    f_read: DeviceReader,
+    f_write: DeviceWriter,
+    record: &RecordConfig,
+    sysname: &str,
+    ds_type: DSType,
+) -> (DeviceReader, DeviceWriter) {
+    let started = open_capture(&record.path, record).and_then(|mut capture| {
+        let header = format!(
+            "{} {} {} {}\n",
+            CAPTURE_MAGIC,
+            CAPTURE_VERSION,
+            config::type_name(ds_type),
+            sysname
+        );
+        if capture.size + header.len() as u64 + STOP_LINE_MAX > capture.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "file reached size limit",
+            ));
+        }
+        capture.write_line(&header)?;
+        Ok(capture)
+    });
+    let capture = match started {
+        Ok(capture) => Arc::new(Mutex::new(capture)),
+        Err(e) => {
+            warn!(
+                controller = sysname;
+                "Could not record to {}: {}", record.path.display(), e
+            );
+            return (f_read, f_write);
+        }
+    };
+    info!(controller = sysname; "Recording to {}", record.path.display());
+    let reader = RecordingReader {
+        inner: f_read,
+        capture: Arc::clone(&capture),
+    };
+    let writer = RecordingWriter {
+        inner: f_write,
+        capture,
+    };
+    (Box::new(reader), Box::new(writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(max_size: u64, max_duration: Duration) -> Capture<Vec<u8>> {
+        Capture {
+            file: Vec::new(),
+            start: Instant::now(),
+            size: 0,
+            max_size,
+            max_duration,
+            stopped: false,
+        }
+    }
+
+    #[test]
+    fn record_lines() {
+        assert_eq!(
+            format_record(
+                Duration::from_micros(4012),
+                Direction::Input,
+                &[0x31, 0x10, 0x80]
+            ),
+            "4012 I 31 10 80\n"
+        );
+        let mut capture = capture(1024, Duration::from_secs(60));
+        capture.record(Direction::Output, &[0x05, 0xFF]);
+        let text = String::from_utf8(capture.file.clone()).unwrap();
+        let (time, record) = text.split_once(' ').unwrap();
+        assert!(time.parse::<u64>().is_ok());
+        assert_eq!(record, "O 05 FF\n");
+        assert_eq!(capture.size, text.len() as u64);
+    }
+
+    #[test]
+    fn parse_capture() {
+        let text = "# ds4net capture 1 dualsense-bt hidraw3\n\
+                    4012 I 31 10 80\n\
+                    4230 O 31 00 10\n\
+                    # stopped: time limit\n\
+                    # ds4net capture 1 ds4-usb living room pad\n\
+                    15 I 01 FF\n";
+        let sessions = parse_sessions(text).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].ds_type, DSType::SenseBT);
+        assert_eq!(sessions[0].sysname, "hidraw3");
+        assert_eq!(
+            sessions[0].records[1],
+            Record {
+                time: Duration::from_micros(4230),
+                direction: Direction::Output,
+                report: vec![0x31, 0x00, 0x10],
+            }
+        );
+        assert_eq!(sessions[1].ds_type, DSType::DS4USB);
+        assert_eq!(sessions[1].sysname, "living room pad");
+        assert_eq!(sessions[1].records[0].report, [0x01, 0xFF]);
+        for bad in [
+            "15 I 01\n",
+            "# ds4net capture 2 ds4-usb hidraw4\n",
+            "# ds4net capture 1 ds4-usb\n",
+            "# ds4net capture 1 ds5 hidraw4\n",
+            "# ds4net capture 1 ds4-usb hidraw4\n15 X 01\n",
+            "# ds4net capture 1 ds4-usb hidraw4\n15 I 0G\n",
+        ] {
+            assert!(parse_sessions(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut capture = capture(96, Duration::from_secs(60));
+        for _ in 0..10 {
+            capture.record(Direction::Input, &[0x01; 8]);
+        }
+        assert!(capture.size <= capture.max_size);
+        let text = String::from_utf8(capture.file).unwrap();
+        assert_eq!(text.lines().filter(|line| line.contains(" I ")).count(), 2);
+        assert!(text.ends_with("# stopped: size limit\n"));
+    }
+
+    #[test]
+    fn full_file_is_not_recorded() {
+        let path = std::env::temp_dir().join(format!("ds4net-{}-full.capture", std::process::id()));
+        std::fs::write(&path, "# stopped: size limit\n").unwrap();
+        let record = RecordConfig {
+            controller: String::from("hidraw3"),
+            path: path.clone(),
+            max_size: 40,
+            max_duration: 60,
+        };
+        let (mut reader, _) = super::record(
+            Box::new(io::repeat(0x01)),
+            Box::new(io::sink()),
+            &record,
+            "hidraw3",
+            DSType::DS4USB,
+        );
+        reader.read_exact(&mut [0; 64]).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "# stopped: size limit\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn time_limit() {
+        let mut capture = capture(1024, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        capture.record(Direction::Input, &[0x01]);
+        capture.record(Direction::Input, &[0x01]);
+        assert_eq!(capture.file, b"# stopped: time limit\n");
+    }
+}
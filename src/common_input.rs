@@ -1,15 +1,259 @@
-use std::fs::File;
+use std::fmt;
 use std::io;
 
+use crc::{Crc, CRC_32_ISO_HDLC};
+
 pub const PACKET_LEN_USB: usize = 64;
 pub const PACKET_LEN_BT: usize = 78;
 
 pub type DS4PacketInner = [u8; PACKET_LEN_USB];
 
+#[derive(Debug)]
+pub enum PacketError {
+    ShortRead(usize),
+    WrongReportId(u8),
+    BadCrc,
+    Io(io::Error),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::ShortRead(count) => write!(f, "short read of {} bytes", count),
+            PacketError::WrongReportId(id) => write!(f, "unexpected report ID 0x{:02X}", id),
+            PacketError::BadCrc => write!(f, "bad CRC"),
+            PacketError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for PacketError {
+    fn from(e: io::Error) -> Self {
+        PacketError::Io(e)
+    }
+}
+
+// CRC32 of BT reports, both input and output ones
+pub const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// BT input reports end with CRC32 of 0xA1 header byte and the rest of report
+pub fn is_valid_checksum_bt(packet: &[u8]) -> bool {
+    let (data, checksum) = packet.split_at(packet.len() - 4);
+    let mut digest = CRC.digest();
+    digest.update(&[0xA1]);
+    digest.update(data);
+    digest.finalize().to_le_bytes() == checksum
+}
+
 pub trait Packet {
-    fn read(&mut self, f: &mut File) -> io::Result<()>;
-    fn battery_capacity(&self) -> u8;
+    // Takes whole input report, as it is read from device
+    fn parse(&mut self, buf: &[u8]) -> Result<(), PacketError>;
+    fn decode(&self) -> ControllerState;
     fn to_ds4_packet(&self) -> DS4PacketInner;
     fn is_valid(&self) -> bool;
     fn get_size(&self) -> usize;
 }
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DPad {
+    Up,
+    UpRight,
+    Right,
+    DownRight,
+    Down,
+    DownLeft,
+    Left,
+    UpLeft,
+    #[default]
+    Neutral,
+}
+
+impl DPad {
+    pub fn from_hat(hat: u8) -> Self {
+        match hat & 0x0F {
+            0 => DPad::Up,
+            1 => DPad::UpRight,
+            2 => DPad::Right,
+            3 => DPad::DownRight,
+            4 => DPad::Down,
+            5 => DPad::DownLeft,
+            6 => DPad::Left,
+            7 => DPad::UpLeft,
+            _ => DPad::Neutral,
+        }
+    }
+    pub fn to_hat(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub square: bool,
+    pub cross: bool,
+    pub circle: bool,
+    pub triangle: bool,
+    pub l1: bool,
+    pub r1: bool,
+    pub l2: bool,
+    pub r2: bool,
+    pub share: bool,
+    pub options: bool,
+    pub l3: bool,
+    pub r3: bool,
+    pub ps: bool,
+    pub touchpad: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TouchPoint {
+    pub active: bool,
+    pub id: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl TouchPoint {
+    // bit 7 of first byte is "not touching", lower 7 bits are finger id,
+    // then 12-bit x and 12-bit y, packed little-endian
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            active: data[0] & 0x80 == 0,
+            id: data[0] & 0x7F,
+            x: u16::from(data[1]) | (u16::from(data[2] & 0x0F) << 8),
+            y: u16::from(data[2] >> 4) | (u16::from(data[3]) << 4),
+        }
+    }
+    pub fn to_bytes(self) -> [u8; 4] {
+        [
+            (u8::from(!self.active) << 7) | (self.id & 0x7F),
+            self.x as u8,
+            ((self.x >> 8) as u8 & 0x0F) | ((self.y as u8 & 0x0F) << 4),
+            (self.y >> 4) as u8,
+        ]
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChargingState {
+    #[default]
+    Discharging,
+    Charging,
+    Full,
+    Error,
+}
+
+// level is in percent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryStatus {
+    pub level: u8,
+    pub state: ChargingState,
+    pub cable: bool,
+}
+
+// Decoded state of controller, in DS4 units: touchpad coordinates
+// are 1920x942, timestamp is in 0.33us units
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ControllerState {
+    pub lx: u8,
+    pub ly: u8,
+    pub rx: u8,
+    pub ry: u8,
+    pub l2: u8,
+    pub r2: u8,
+    pub dpad: DPad,
+    pub buttons: Buttons,
+    pub gyro: [i16; 3],
+    pub accel: [i16; 3],
+    pub touch: [TouchPoint; 2],
+    pub timestamp: u32,
+    pub temperature: u8,
+    pub counter: u8,
+    pub battery: BatteryStatus,
+    pub headphones: bool,
+    pub microphone: bool,
+}
+
+fn bit(byte: u8, mask: u8) -> bool {
+    byte & mask != 0
+}
+
+impl ControllerState {
+    // d-pad and face buttons, same layout on DS4 and DualSense
+    pub fn set_buttons0(&mut self, byte: u8) {
+        self.dpad = DPad::from_hat(byte);
+        self.buttons.square = bit(byte, 0x10);
+        self.buttons.cross = bit(byte, 0x20);
+        self.buttons.circle = bit(byte, 0x40);
+        self.buttons.triangle = bit(byte, 0x80);
+    }
+
+    // L1, R1, L2, R2, Share/Create, Options, L3, R3, same on DS4 and DualSense
+    pub fn set_buttons1(&mut self, byte: u8) {
+        self.buttons.l1 = bit(byte, 0x01);
+        self.buttons.r1 = bit(byte, 0x02);
+        self.buttons.l2 = bit(byte, 0x04);
+        self.buttons.r2 = bit(byte, 0x08);
+        self.buttons.share = bit(byte, 0x10);
+        self.buttons.options = bit(byte, 0x20);
+        self.buttons.l3 = bit(byte, 0x40);
+        self.buttons.r3 = bit(byte, 0x80);
+    }
+
+    pub fn set_motion(&mut self, data: &[u8]) {
+        for i in 0..3 {
+            self.gyro[i] = i16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+            self.accel[i] = i16::from_le_bytes([data[i * 2 + 6], data[i * 2 + 7]]);
+        }
+    }
+
+    pub fn to_ds4_packet(&self) -> DS4PacketInner {
+        let mut pkt: DS4PacketInner = [0; PACKET_LEN_USB];
+        let b = &self.buttons;
+        pkt[1] = self.lx;
+        pkt[2] = self.ly;
+        pkt[3] = self.rx;
+        pkt[4] = self.ry;
+        pkt[5] = self.dpad.to_hat()
+            | (u8::from(b.square) << 4)
+            | (u8::from(b.cross) << 5)
+            | (u8::from(b.circle) << 6)
+            | (u8::from(b.triangle) << 7);
+        pkt[6] = u8::from(b.l1)
+            | (u8::from(b.r1) << 1)
+            | (u8::from(b.l2) << 2)
+            | (u8::from(b.r2) << 3)
+            | (u8::from(b.share) << 4)
+            | (u8::from(b.options) << 5)
+            | (u8::from(b.l3) << 6)
+            | (u8::from(b.r3) << 7);
+        // upper 6 bits are report counter
+        pkt[7] = (self.counter << 2) | u8::from(b.ps) | (u8::from(b.touchpad) << 1);
+        pkt[8] = self.l2;
+        pkt[9] = self.r2;
+        // DS4 timestamp is in 5.33us units
+        pkt[10..12].copy_from_slice(&((self.timestamp / 16) as u16).to_le_bytes());
+        pkt[12] = self.temperature;
+        for i in 0..3 {
+            pkt[13 + i * 2..15 + i * 2].copy_from_slice(&self.gyro[i].to_le_bytes());
+            pkt[19 + i * 2..21 + i * 2].copy_from_slice(&self.accel[i].to_le_bytes());
+        }
+        // lower nibble is battery level (0-10, 11 is full, 14 is error when on cable),
+        // 0x10 is cable, 0x20 is headphones, 0x40 is microphone
+        let level = match self.battery.state {
+            ChargingState::Full => 11,
+            ChargingState::Error => 14,
+            _ => (self.battery.level / 10).min(10),
+        };
+        pkt[30] = level
+            | (u8::from(self.battery.cable) << 4)
+            | (u8::from(self.headphones) << 5)
+            | (u8::from(self.microphone) << 6);
+        // one touch report, with report counter as touch timestamp
+        pkt[33] = 1;
+        pkt[34] = self.counter;
+        pkt[35..39].copy_from_slice(&self.touch[0].to_bytes());
+        pkt[39..43].copy_from_slice(&self.touch[1].to_bytes());
+        pkt
+    }
+}
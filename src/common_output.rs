@@ -1,22 +1,134 @@
-use std::fs::File;
-use std::io;
+use crate::common_input::{BatteryStatus, CRC};
 
-use crc::{Crc, CRC_32_ISO_HDLC};
+// Lightbar color, which is used when client does not set its own
+pub const DEFAULT_COLOR: (u8, u8, u8) = (0, 0, 255);
+
+// Flash patterns: time to stay bright and dark in 10ms units (255 = 2.5 seconds)
+pub const FLASH_NONE: (u8, u8) = (0, 0);
+pub const FLASH_CHARGING: (u8, u8) = (100, 100);
+pub const FLASH_CHARGING_ERROR: (u8, u8) = (25, 25);
+pub const FLASH_LOW_BATTERY: (u8, u8) = (50, 50);
+// short blink every two seconds, when gamepad is not used by any client
+pub const FLASH_WAITING: (u8, u8) = (20, 180);
+
+pub const LOW_BATTERY_LEVEL: u8 = 10;
+
+// Trigger travel is split into 10 zones
+pub const TRIGGER_ZONES: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Left,
+    Right,
+}
+
+// Adaptive trigger effects, positions are zones 0-9, strength and amplitude are 1-8
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEffect {
+    #[default]
+    Off,
+    // resistance from start to the end of travel
+    Resistance {
+        start: u8,
+        strength: u8,
+    },
+    // resistance between start (2-7) and end (start+1 - 8), which snaps when passed
+    Weapon {
+        start: u8,
+        end: u8,
+        strength: u8,
+    },
+    // vibration from start to the end of travel, frequency is in Hz
+    Vibration {
+        start: u8,
+        amplitude: u8,
+        frequency: u8,
+    },
+}
+
+impl TriggerEffect {
+    pub fn is_valid(self) -> bool {
+        match self {
+            TriggerEffect::Off => true,
+            TriggerEffect::Resistance { start, strength } => {
+                start < TRIGGER_ZONES && (1..=8).contains(&strength)
+            }
+            TriggerEffect::Weapon {
+                start,
+                end,
+                strength,
+            } => (2..=7).contains(&start) && end > start && end <= 8 && (1..=8).contains(&strength),
+            TriggerEffect::Vibration {
+                start,
+                amplitude,
+                frequency,
+            } => start < TRIGGER_ZONES && (1..=8).contains(&amplitude) && frequency > 0,
+        }
+    }
+}
+
+// What five player LEDs under touchpad show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerLeds {
+    // battery gauge, blinking while charging
+    Battery,
+    // number of player 1-4, with the same patterns as PlayStation uses
+    Player(u8),
+}
+
+impl PlayerLeds {
+    pub fn is_valid(self) -> bool {
+        match self {
+            PlayerLeds::Battery => true,
+            PlayerLeds::Player(number) => (1..=4).contains(&number),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MuteLed {
+    #[default]
+    Off = 0,
+    On = 1,
+    Pulse = 2,
+}
 
 pub trait Controls {
     fn set_color(&mut self, r: u8, g: u8, b: u8);
     fn set_rumble(&mut self, large: u8, small: u8);
-    fn set_battery(&mut self, level: u8);
-    fn write_packet_usb(&self, f_write: &mut File) -> io::Result<()>;
-    fn write_packet_bt(&mut self, f_write: &mut File) -> io::Result<()>;
+    fn set_battery(&mut self, status: BatteryStatus);
+    fn set_flash(&mut self, bright: u8, dark: u8);
+    // returns false, if gamepad has no adaptive triggers
+    fn set_trigger_effect(&mut self, trigger: Trigger, effect: TriggerEffect) -> bool;
+    // returns false, if gamepad has no player or mute LEDs
+    fn set_player_leds(&mut self, leds: PlayerLeds) -> bool;
+    fn set_mute_led(&mut self, mute: MuteLed) -> bool;
+    // returns false, if volume of gamepad could not be set
+    fn set_volume(&mut self, left: u8, right: u8, speaker: u8) -> bool;
+    // Report interval over BT, only DS4 has it
+    fn set_bt_latency(&mut self, _latency: u8) {}
+    // Called periodically, when there are no other updates,
+    // returns true if packet should be written again (for animations)
+    fn tick(&mut self) -> bool {
+        false
+    }
+    // Returns true, if flash is animated by tick, because gamepad could not flash by itself
+    fn is_flash_emulated(&self) -> bool {
+        false
+    }
+    // Output, which could be written within audio report over BT, only DS4 has it
+    fn audio_output(&self) -> Option<Vec<u8>> {
+        None
+    }
+    // Output reports to be written to device as they are,
+    // BT one is mutable, because DualSense counts reports
+    fn report_usb(&self) -> Vec<u8>;
+    fn report_bt(&mut self) -> Vec<u8>;
 }
 
-const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-
 pub fn calculate_checksum_bt(packet: &[u8]) -> [u8; 4] {
-    let mut full_packet = [0u8; 75];
-    full_packet[0] = 0xA2;
-    full_packet[1..].copy_from_slice(packet);
-    let hasher = CRC.checksum(&full_packet);
-    hasher.to_le_bytes()
+    let mut digest = CRC.digest();
+    digest.update(&[0xA2]);
+    digest.update(packet);
+    digest.finalize().to_le_bytes()
 }
@@ -0,0 +1,818 @@
+// Server configuration, loaded from TOML file, with some settings overridden from command line:
+//
+//   [server]
+//   listen = "::"              # address to bind
+//   port = 9999
+//   log_level = "info"         # error, warn, info, debug or trace
+//   log_output = "stderr"      # stderr or journald
+//   log_modules = { udevmon = "debug" }  # levels of single modules
+//   allowed_clients = ["192.168.1.0/24", "::1"]  # addresses or networks, empty allows all
+//
+//   [controller]
+//   allowed_types = ["ds4-usb", "ds4-bt", "dualsense-usb", "dualsense-bt"]
+//   bt_latency = 4             # DS4 BT report interval, 0-15
+//   rumble_scale = 1.0         # rumble from clients is multiplied by it, 0.0-1.0
+//
+//   [lightbar]
+//   idle_color = [0, 0, 255]
+//   connect_color = [0, 255, 0]
+//   connect_flash_ms = 1000    # 0 disables connect animation
+//
+//   [[virtual]]                # virtual gamepad for testing, see virtual_gamepad.rs
+//   name = "virtual0"          # used instead of hidraw name
+//   type = "dualsense-usb"
+//   input = "/tmp/virtual0.in"     # file or FIFO with input reports
+//   output = "/tmp/virtual0.out"   # output reports are appended here
+//   mac = "00:11:22:33:44:55"  # optional
+//   interval_ms = 4            # input reports are not read more often
+//
+//   [[replay]]                 # virtual gamepad, which replays capture, see replay.rs
+//   name = "replay0"           # used instead of hidraw name
+//   capture = "/var/lib/ds4net/menu.capture"
+//   session = 1                # number of recorded session in capture file
+//   speed = 1.0                # 0.01-100, 2.0 replays twice as fast
+//   loop = false               # start again after the end of session
+//   output = "/tmp/replay0.out"    # optional, output reports are appended here
+//   mac = "00:11:22:33:44:55"  # optional
+//
+//   [[record]]                 # record reports of controller, see capture.rs
+//   controller = "hidraw3"     # sysname or MAC
+//   path = "/var/log/ds4net/hidraw3.capture"
+//   max_size = 10485760        # recording stops, when file is this large (bytes)
+//   max_duration = 600         # or session is this long (seconds)
+//
+// Configuration is reloaded on SIGHUP, changes of listen address, port, virtual and replayed
+// gamepads need restart, recordings start with the next session of controller.
+// Clients and controller types, which are not allowed anymore, are disconnected.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::common_output::DEFAULT_COLOR;
+use crate::controls_ds4::DEFAULT_LATENCY;
+use crate::logging::{Filters, Output};
+use crate::udevmon::DSType;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/ds4net.toml";
+pub const DEFAULT_PORT: u16 = 9999;
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+const LOG_OUTPUTS: [(&str, Output); 2] =
+    [("stderr", Output::Stderr), ("journald", Output::Journald)];
+const TYPE_NAMES: [(&str, DSType); 4] = [
+    ("ds4-usb", DSType::DS4USB),
+    ("ds4-bt", DSType::DS4BT),
+    ("dualsense-usb", DSType::SenseUSB),
+    ("dualsense-bt", DSType::SenseBT),
+];
+
+const USAGE: &str = "Usage: ds4net-rust [options]
+  -c, --config PATH      configuration file (default /etc/ds4net.toml)
+  -l, --listen ADDRESS   address to listen on
+  -p, --port PORT        UDP port to listen on
+      --record CONTROLLER=PATH
+                         record reports of controller (sysname or MAC) to PATH
+      --log-level LEVEL  error, warn, info, debug or trace
+      --log-output OUT   stderr or journald
+  -h, --help             show this help";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+    Usage(String),
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "Invalid configuration: {}", msg),
+            ConfigError::Usage(msg) => write!(f, "{}\n{}", msg, USAGE),
+            ConfigError::Help => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: IpAddr,
+    pub port: u16,
+    pub log_level: String,
+    pub log_output: String,
+    pub log_modules: BTreeMap<String, String>,
+    pub allowed_clients: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            log_level: "info".to_string(),
+            log_output: "stderr".to_string(),
+            log_modules: BTreeMap::new(),
+            allowed_clients: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerConfig {
+    pub allowed_types: Vec<String>,
+    pub bt_latency: u8,
+    pub rumble_scale: f32,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            allowed_types: TYPE_NAMES
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            bt_latency: DEFAULT_LATENCY,
+            rumble_scale: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LightbarConfig {
+    pub idle_color: [u8; 3],
+    pub connect_color: [u8; 3],
+    pub connect_flash_ms: u64,
+}
+
+impl Default for LightbarConfig {
+    fn default() -> Self {
+        Self {
+            idle_color: [DEFAULT_COLOR.0, DEFAULT_COLOR.1, DEFAULT_COLOR.2],
+            connect_color: [0, 255, 0],
+            connect_flash_ms: 1000,
+        }
+    }
+}
+
+fn default_interval_ms() -> u64 {
+    4
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ds_type: String,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+impl VirtualConfig {
+    // Type is checked by validate
+    pub fn ds_type(&self) -> DSType {
+        type_by_name(&self.ds_type).unwrap_or(DSType::DS4USB)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+fn default_session() -> usize {
+    1
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub name: String,
+    pub capture: PathBuf,
+    #[serde(default = "default_session")]
+    pub session: usize,
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+    #[serde(default, rename = "loop")]
+    pub looped: bool,
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub mac: Option<String>,
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_duration() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    pub controller: String,
+    pub path: PathBuf,
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_max_duration")]
+    pub max_duration: u64,
+}
+
+impl RecordConfig {
+    fn new(controller: String, path: PathBuf) -> Self {
+        Self {
+            controller,
+            path,
+            max_size: default_max_size(),
+            max_duration: default_max_duration(),
+        }
+    }
+
+    fn matches(&self, sysname: &str, mac: Option<&str>) -> bool {
+        self.controller == sysname
+            || mac.is_some_and(|mac| self.controller.eq_ignore_ascii_case(mac))
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        Duration::from_secs(self.max_duration)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub controller: ControllerConfig,
+    pub lightbar: LightbarConfig,
+    #[serde(rename = "virtual")]
+    pub virtual_gamepads: Vec<VirtualConfig>,
+    #[serde(rename = "replay")]
+    pub replays: Vec<ReplayConfig>,
+    #[serde(rename = "record")]
+    pub recordings: Vec<RecordConfig>,
+}
+
+pub fn type_by_name(name: &str) -> Option<DSType> {
+    TYPE_NAMES
+        .iter()
+        .find(|(v, _)| *v == name)
+        .map(|(_, ds_type)| *ds_type)
+}
+
+// Address or network with prefix length, e.g. 192.168.1.0/24
+fn parse_network(text: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match text.split_once('/') {
+        Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+        None => (text.parse().ok()?, None),
+    };
+    let max_prefix = if matches!(addr, IpAddr::V4(_)) {
+        32
+    } else {
+        128
+    };
+    match prefix {
+        Some(prefix) if prefix > max_prefix => None,
+        prefix => Some((addr, prefix.unwrap_or(max_prefix))),
+    }
+}
+
+// IPv4 clients of dual stack socket are seen as IPv4-mapped IPv6 addresses
+fn in_network(addr: IpAddr, (network, prefix): (IpAddr, u8)) -> bool {
+    let (addr, network, bits) = match (addr.to_canonical(), network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            (u32::from(addr) as u128, u32::from(network) as u128, 32)
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => (u128::from(addr), u128::from(network), 128),
+        _ => return false,
+    };
+    (addr ^ network)
+        .checked_shr(bits - prefix as u32)
+        .unwrap_or(0)
+        == 0
+}
+
+pub fn type_name(ds_type: DSType) -> &'static str {
+    TYPE_NAMES
+        .iter()
+        .find(|(_, v)| *v == ds_type)
+        .map_or("", |(name, _)| name)
+}
+
+// Settings, which are used by control thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputSettings {
+    pub idle_color: (u8, u8, u8),
+    pub rumble_scale: f32,
+    pub bt_latency: u8,
+}
+
+impl OutputSettings {
+    pub fn scale_rumble(&self, value: u8) -> u8 {
+        (value as f32 * self.rumble_scale).round() as u8
+    }
+}
+
+// Command line options, which override configuration file
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    config: Option<PathBuf>,
+    listen: Option<IpAddr>,
+    port: Option<u16>,
+    log_level: Option<String>,
+    log_output: Option<String>,
+    record: Vec<RecordConfig>,
+}
+
+fn parse_value<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+) -> Result<T, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::Usage(format!("{} needs a value", option)))?;
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("Bad value {} for {}", value, option)))
+}
+
+// Parses command line without program name
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, ConfigError> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => parsed.config = Some(parse_value(&arg, args.next())?),
+            "-l" | "--listen" => parsed.listen = Some(parse_value(&arg, args.next())?),
+            "-p" | "--port" => parsed.port = Some(parse_value(&arg, args.next())?),
+            "--log-level" => parsed.log_level = Some(parse_value(&arg, args.next())?),
+            "--log-output" => parsed.log_output = Some(parse_value(&arg, args.next())?),
+            "--record" => {
+                let value: String = parse_value(&arg, args.next())?;
+                let (controller, path) = value
+                    .split_once('=')
+                    .filter(|(controller, path)| !controller.is_empty() && !path.is_empty())
+                    .ok_or_else(|| {
+                        ConfigError::Usage(format!("Bad value {} for {}", value, arg))
+                    })?;
+                parsed.record.push(RecordConfig::new(
+                    controller.to_string(),
+                    PathBuf::from(path),
+                ));
+            }
+            "-h" | "--help" => return Err(ConfigError::Help),
+            _ => return Err(ConfigError::Usage(format!("Unknown option {}", arg))),
+        }
+    }
+    Ok(parsed)
+}
+
+impl Config {
+    pub fn parse(text: &str, path: &Path) -> Result<Self, ConfigError> {
+        let config: Config =
+            toml::from_str(text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Missing file is not an error, if it was not given explicitly
+    pub fn load(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text, path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Default::default()),
+            Err(e) => Err(ConfigError::Io(path.to_path_buf(), e)),
+        }
+    }
+
+    // Loads configuration file, given on command line or default one
+    pub fn from_args(args: &Args) -> Result<Self, ConfigError> {
+        let path = match &args.config {
+            Some(path) => path.clone(),
+            None => PathBuf::from(DEFAULT_CONFIG_PATH),
+        };
+        let mut config = Self::load(&path, args.config.is_some())?;
+        if let Some(listen) = args.listen {
+            config.server.listen = listen;
+        }
+        if let Some(port) = args.port {
+            config.server.port = port;
+        }
+        if let Some(log_level) = &args.log_level {
+            config.server.log_level = log_level.clone();
+        }
+        if let Some(log_output) = &args.log_output {
+            config.server.log_output = log_output.clone();
+        }
+        config.recordings.extend(args.record.iter().cloned());
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let levels = std::iter::once(("server.log_level".to_string(), &self.server.log_level))
+            .chain(
+                self.server
+                    .log_modules
+                    .iter()
+                    .map(|(module, level)| (format!("server.log_modules.{}", module), level)),
+            );
+        for (name, level) in levels {
+            if !LOG_LEVELS.contains(&level.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "{} should be one of {}, not {}",
+                    name,
+                    LOG_LEVELS.join(", "),
+                    level
+                )));
+            }
+        }
+        if !LOG_OUTPUTS
+            .iter()
+            .any(|(name, _)| *name == self.server.log_output)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "server.log_output should be stderr or journald, not {}",
+                self.server.log_output
+            )));
+        }
+        for network in &self.server.allowed_clients {
+            if parse_network(network).is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "bad address or network {} in server.allowed_clients",
+                    network
+                )));
+            }
+        }
+        for name in &self.controller.allowed_types {
+            if type_by_name(name).is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown controller type {} in controller.allowed_types",
+                    name
+                )));
+            }
+        }
+        if self.controller.bt_latency > 15 {
+            return Err(ConfigError::Invalid(format!(
+                "controller.bt_latency should be 0-15, not {}",
+                self.controller.bt_latency
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.controller.rumble_scale) {
+            return Err(ConfigError::Invalid(format!(
+                "controller.rumble_scale should be 0.0-1.0, not {}",
+                self.controller.rumble_scale
+            )));
+        }
+        for gamepad in &self.virtual_gamepads {
+            if type_by_name(&gamepad.ds_type).is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown controller type {} of virtual gamepad {}",
+                    gamepad.ds_type, gamepad.name
+                )));
+            }
+        }
+        for replay in &self.replays {
+            if !(0.01..=100.0).contains(&replay.speed) {
+                return Err(ConfigError::Invalid(format!(
+                    "speed of replayed gamepad {} should be 0.01-100, not {}",
+                    replay.name, replay.speed
+                )));
+            }
+            if replay.session == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "session of replayed gamepad {} should be positive",
+                    replay.name
+                )));
+            }
+        }
+        // virtual and replayed gamepads share names with hidraw ones
+        let names = self
+            .virtual_gamepads
+            .iter()
+            .map(|gamepad| &gamepad.name)
+            .chain(self.replays.iter().map(|replay| &replay.name));
+        for (i, name) in names.clone().enumerate() {
+            if name.is_empty() || name.starts_with("hidraw") {
+                return Err(ConfigError::Invalid(format!(
+                    "name of virtual gamepad should not be empty or start with hidraw, not {:?}",
+                    name
+                )));
+            }
+            if names.clone().take(i).any(|other| other == name) {
+                return Err(ConfigError::Invalid(format!(
+                    "virtual gamepad {} is defined twice",
+                    name
+                )));
+            }
+        }
+        for record in &self.recordings {
+            if record.max_size == 0 || record.max_duration == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "limits of recording of {} should not be 0",
+                    record.controller
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Takes settings, which could not be changed without restart, from running
+    // configuration and returns names of those, which were changed
+    pub fn keep_restart_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.server.listen != running.server.listen {
+            changed.push("server.listen");
+            self.server.listen = running.server.listen;
+        }
+        if self.server.port != running.server.port {
+            changed.push("server.port");
+            self.server.port = running.server.port;
+        }
+        if self.virtual_gamepads != running.virtual_gamepads {
+            changed.push("virtual");
+            self.virtual_gamepads = running.virtual_gamepads.clone();
+        }
+        if self.replays != running.replays {
+            changed.push("replay");
+            self.replays = running.replays.clone();
+        }
+        changed
+    }
+
+    // Levels are checked by validate
+    pub fn log_filters(&self) -> Filters {
+        let level = |name: &String| name.parse().unwrap_or(LevelFilter::Info);
+        Filters {
+            level: level(&self.server.log_level),
+            modules: self
+                .server
+                .log_modules
+                .iter()
+                .map(|(module, name)| (module.clone(), level(name)))
+                .collect(),
+        }
+    }
+
+    pub fn log_output(&self) -> Output {
+        LOG_OUTPUTS
+            .iter()
+            .find(|(name, _)| *name == self.server.log_output)
+            .map_or(Output::Stderr, |(_, output)| *output)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.listen, self.server.port)
+    }
+
+    pub fn is_allowed(&self, ds_type: DSType) -> bool {
+        TYPE_NAMES.iter().any(|(name, v)| {
+            *v == ds_type && self.controller.allowed_types.iter().any(|t| t == name)
+        })
+    }
+
+    // Networks are checked by validate
+    pub fn is_client_allowed(&self, addr: IpAddr) -> bool {
+        self.server.allowed_clients.is_empty()
+            || self
+                .server
+                .allowed_clients
+                .iter()
+                .filter_map(|network| parse_network(network))
+                .any(|network| in_network(addr, network))
+    }
+
+    // Recording of controller, if it is asked for
+    pub fn recording(&self, sysname: &str, mac: Option<&str>) -> Option<&RecordConfig> {
+        self.recordings
+            .iter()
+            .find(|record| record.matches(sysname, mac))
+    }
+
+    pub fn connect_flash(&self) -> Duration {
+        Duration::from_millis(self.lightbar.connect_flash_ms)
+    }
+
+    pub fn output_settings(&self) -> OutputSettings {
+        let [r, g, b] = self.lightbar.idle_color;
+        OutputSettings {
+            idle_color: (r, g, b),
+            rumble_scale: self.controller.rumble_scale,
+            bt_latency: self.controller.bt_latency,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn parse_config() {
+        let text = r#"
+            [server]
+            listen = "127.0.0.1"
+            port = 10000
+            log_output = "journald"
+            log_modules = { udevmon = "debug" }
+            allowed_clients = ["192.168.1.0/24", "::1", "10.1.2.3"]
+
+            [controller]
+            allowed_types = ["dualsense-bt"]
+            rumble_scale = 0.5
+
+            [lightbar]
+            idle_color = [255, 0, 255]
+        "#;
+        let config = Config::parse(text, Path::new("test.toml")).unwrap();
+        assert_eq!(config.listen_addr(), "127.0.0.1:10000".parse().unwrap());
+        assert!(config.is_allowed(DSType::SenseBT));
+        assert!(!config.is_allowed(DSType::DS4USB));
+        for (addr, allowed) in [
+            ("192.168.1.77", true),
+            ("192.168.2.1", false),
+            ("::ffff:192.168.1.5", true),
+            ("::1", true),
+            ("::2", false),
+            ("10.1.2.3", true),
+            ("10.1.2.4", false),
+        ] {
+            assert_eq!(
+                config.is_client_allowed(addr.parse().unwrap()),
+                allowed,
+                "{}",
+                addr
+            );
+        }
+        assert!(Config::default().is_client_allowed("192.168.2.1".parse().unwrap()));
+        let settings = config.output_settings();
+        assert_eq!(settings.idle_color, (255, 0, 255));
+        assert_eq!(settings.scale_rumble(255), 128);
+        assert_eq!(settings.bt_latency, DEFAULT_LATENCY);
+        assert_eq!(config.connect_flash(), Duration::from_secs(1));
+        assert_eq!(config.log_output(), Output::Journald);
+        let filters = config.log_filters();
+        assert_eq!(filters.level, LevelFilter::Info);
+        assert_eq!(filters.modules["udevmon"], LevelFilter::Debug);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let path = Path::new("test.toml");
+        for text in [
+            "[controller]\nallowed_types = [\"ds5\"]",
+            "[controller]\nrumble_scale = 1.5",
+            "[controller]\nbt_latency = 16",
+            "[server]\nlog_level = \"loud\"",
+            "[server]\nlog_modules = { udevmon = \"loud\" }",
+            "[server]\nlog_output = \"syslog\"",
+            "[server]\nport = 70000",
+            "[server]\nallowed_clients = [\"10.0.0.0/33\"]",
+            "[server]\nallowed_clients = [\"localhost\"]",
+            "[lightbar]\ncolor = [0, 0, 0]",
+            "[[virtual]]\nname = \"v\"\ntype = \"ds5\"\ninput = \"i\"\noutput = \"o\"",
+            "[[virtual]]\nname = \"hidraw0\"\ntype = \"ds4-bt\"\ninput = \"i\"\noutput = \"o\"",
+            "[[virtual]]\nname = \"v\"\ntype = \"ds4-bt\"\ninput = \"i\"",
+        ] {
+            assert!(Config::parse(text, path).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn virtual_gamepads() {
+        let text = r#"
+            [[virtual]]
+            name = "virtual0"
+            type = "dualsense-bt"
+            input = "/tmp/virtual0.in"
+            output = "/tmp/virtual0.out"
+
+            [[virtual]]
+            name = "virtual1"
+            type = "ds4-usb"
+            input = "/tmp/virtual1.in"
+            output = "/tmp/virtual1.out"
+            interval_ms = 0
+        "#;
+        let config = Config::parse(text, Path::new("test.toml")).unwrap();
+        let [first, second] = &config.virtual_gamepads[..] else {
+            panic!("two virtual gamepads expected");
+        };
+        assert_eq!(first.ds_type(), DSType::SenseBT);
+        assert_eq!(first.interval(), Duration::from_millis(4));
+        assert_eq!(first.mac, None);
+        assert_eq!(second.ds_type(), DSType::DS4USB);
+        assert!(second.interval().is_zero());
+        let twice = text.replace("virtual1", "virtual0");
+        assert!(Config::parse(&twice, Path::new("test.toml")).is_err());
+    }
+
+    #[test]
+    fn replays() {
+        let text = r#"
+            [[replay]]
+            name = "replay0"
+            capture = "/tmp/menu.capture"
+            speed = 2.0
+            loop = true
+        "#;
+        let config = Config::parse(text, Path::new("test.toml")).unwrap();
+        let replay = &config.replays[0];
+        assert_eq!(replay.session, 1);
+        assert_eq!(replay.speed, 2.0);
+        assert!(replay.looped);
+        assert_eq!(replay.output, None);
+        let path = Path::new("test.toml");
+        for speed in ["0.0", "1e-30", "1000.0", "nan"] {
+            assert!(Config::parse(&text.replace("2.0", speed), path).is_err());
+        }
+        assert!(Config::parse(&text.replace("loop", "session = 0\nloop"), path).is_err());
+        let virtual_too = format!(
+            "{}[[virtual]]\nname = \"replay0\"\ntype = \"ds4-bt\"\ninput = \"i\"\noutput = \"o\"",
+            text
+        );
+        assert!(Config::parse(&virtual_too, path).is_err());
+    }
+
+    #[test]
+    fn reload_keeps_listen_address() {
+        let running = Config::default();
+        let text = "[server]\nport = 10000\n[controller]\nrumble_scale = 0.5";
+        let mut config = Config::parse(text, Path::new("test.toml")).unwrap();
+        assert_eq!(config.keep_restart_settings(&running), ["server.port"]);
+        assert_eq!(config.listen_addr(), running.listen_addr());
+        assert_eq!(config.controller.rumble_scale, 0.5);
+    }
+
+    #[test]
+    fn command_line() {
+        // explicitly given file should exist
+        let parsed = parse_args(args("-c /nonexistent/ds4net.toml")).unwrap();
+        assert!(matches!(
+            Config::from_args(&parsed),
+            Err(ConfigError::Io(..))
+        ));
+        let parsed = parse_args(args("--listen ::1 -p 9000 --log-level debug")).unwrap();
+        assert_eq!(parsed.log_output, None);
+        assert_eq!(parsed.listen, Some("::1".parse().unwrap()));
+        assert_eq!(parsed.port, Some(9000));
+        assert_eq!(parsed.log_level.as_deref(), Some("debug"));
+        assert!(matches!(
+            parse_args(args("--port")),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            parse_args(args("--verbose")),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            parse_args(args("--record hidraw3")),
+            Err(ConfigError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn recordings() {
+        let text = r#"
+            [[record]]
+            controller = "AA:BB:CC:DD:EE:FF"
+            path = "/tmp/sense.capture"
+            max_duration = 60
+        "#;
+        let mut config = Config::parse(text, Path::new("test.toml")).unwrap();
+        let parsed = parse_args(args("--record hidraw3=/tmp/hidraw3.capture")).unwrap();
+        config.recordings.extend(parsed.record);
+        let record = config
+            .recording("hidraw5", Some("aa:bb:cc:dd:ee:ff"))
+            .unwrap();
+        assert_eq!(record.path, Path::new("/tmp/sense.capture"));
+        assert_eq!(record.max_duration(), Duration::from_secs(60));
+        assert_eq!(record.max_size, 10 * 1024 * 1024);
+        let record = config.recording("hidraw3", None).unwrap();
+        assert_eq!(record.path, Path::new("/tmp/hidraw3.capture"));
+        assert!(config.recording("hidraw4", None).is_none());
+        let text = "[[record]]\ncontroller = \"hidraw3\"\npath = \"p\"\nmax_size = 0";
+        assert!(Config::parse(text, Path::new("test.toml")).is_err());
+    }
+}
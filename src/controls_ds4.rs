@@ -1,9 +1,15 @@
-use std::fs::File;
-use std::io;
-use std::io::Write;
+use crate::audio_ds4::AUDIO_OUTPUT_LEN;
+use crate::common_input::{BatteryStatus, ChargingState};
+use crate::common_output::{
+    calculate_checksum_bt, Controls, MuteLed, PlayerLeds, Trigger, TriggerEffect, DEFAULT_COLOR,
+    FLASH_CHARGING, FLASH_CHARGING_ERROR, FLASH_LOW_BATTERY, FLASH_NONE, LOW_BATTERY_LEVEL,
+};
 
-use crate::common_output::{calculate_checksum_bt, Controls};
-const DEFAULT_LATENCY: u8 = 4;
+pub const DEFAULT_LATENCY: u8 = 4;
+// Rumble, lightbar color and flash are set by every report
+const FLAGS: u8 = 0x07;
+// Headphones left/right, microphone and speaker volume
+const FLAGS_VOLUME: u8 = 0xF0;
 
 #[derive(Debug)]
 pub struct DS4Controls {
@@ -16,7 +22,10 @@ pub struct DS4Controls {
     volume_l: u8,
     volume_r: u8,
     volume_speaker: u8,
-    battery: u8,
+    // volume is not touched, until client sets it
+    volume_set: bool,
+    battery: BatteryStatus,
+    flash: (u8, u8),
 }
 
 impl Default for DS4Controls {
@@ -25,46 +34,68 @@ impl Default for DS4Controls {
             large: 0,
             small: 0,
             latency: DEFAULT_LATENCY,
-            red: 0,
-            green: 0,
-            blue: 255,
+            red: DEFAULT_COLOR.0,
+            green: DEFAULT_COLOR.1,
+            blue: DEFAULT_COLOR.2,
             volume_l: 0,
             volume_r: 0,
             volume_speaker: 0,
-            battery: 100,
+            volume_set: false,
+            battery: BatteryStatus {
+                level: 100,
+                ..Default::default()
+            },
+            flash: FLASH_NONE,
         }
     }
 }
 
 impl DS4Controls {
-    fn fill_packet(&self) -> [u8; 7] {
-        let mut pkt = [0; 7];
+    fn flags(&self) -> u8 {
+        if self.volume_set {
+            FLAGS | FLAGS_VOLUME
+        } else {
+            FLAGS
+        }
+    }
+
+    fn fill_packet(&self) -> [u8; 20] {
+        let mut pkt = [0; 20];
         let (mut red, mut green, mut blue) = (self.red, self.green, self.blue);
-        if self.battery == 0 {
-            //.load(Ordering::SeqCst) {
-            red = 255;
-            green = 0;
-            blue = 0;
+        // Time to flash bright and dark is done by gamepad itself
+        let (mut flash_bright, mut flash_dark) = self.flash;
+        match self.battery.state {
+            // slow pulse while charging, if client did not set its own flash
+            ChargingState::Charging if self.flash == FLASH_NONE => {
+                (flash_bright, flash_dark) = FLASH_CHARGING;
+            }
+            // fast red blinking on charging error
+            ChargingState::Error => {
+                (red, green, blue) = (255, 0, 0);
+                (flash_bright, flash_dark) = FLASH_CHARGING_ERROR;
+            }
+            ChargingState::Discharging if self.battery.level <= LOW_BATTERY_LEVEL => {
+                (red, green, blue) = (255, 0, 0);
+                (flash_bright, flash_dark) = FLASH_LOW_BATTERY;
+            }
+            _ => (),
         }
 
         //pkt[0] = 0x05;
         //pkt[1] = 0x07;
         //pkt[0] = pkt[4] in usb
-        eprintln!("Small {}, large {}", self.small, self.large);
         pkt[0] = self.small;
         pkt[1] = self.large;
         pkt[2] = red;
         pkt[3] = green;
         pkt[4] = blue;
-        // Time to flash bright (255 = 2.5 seconds)
-        pkt[5] = 0; // min(flash_led1, 255)
-                    // Time to flash dark (255 = 2.5 seconds)
-        pkt[6] = 0; // min(flash_led2, 255)
-                    //pkt[19] = self.volume_l;
-                    //pkt[20] = self.volume_r;
-                    //pkt[21] = 0x49; // magic
-                    //pkt[22] = self.volume_speaker;
-                    //pkt[23] = 0x85; //magic
+        pkt[5] = flash_bright;
+        pkt[6] = flash_dark;
+        pkt[15] = self.volume_l;
+        pkt[16] = self.volume_r;
+        pkt[17] = 0x49; // magic
+        pkt[18] = self.volume_speaker;
+        pkt[19] = 0x85; //magic
         pkt
     }
 }
@@ -79,34 +110,91 @@ impl Controls for DS4Controls {
         self.large = large;
         self.small = small;
     }
-    fn set_battery(&mut self, level: u8) {
-        self.battery = level;
+    fn set_battery(&mut self, status: BatteryStatus) {
+        self.battery = status;
+    }
+    fn set_flash(&mut self, bright: u8, dark: u8) {
+        self.flash = (bright, dark);
+    }
+    fn set_trigger_effect(&mut self, _trigger: Trigger, _effect: TriggerEffect) -> bool {
+        false
+    }
+    fn set_player_leds(&mut self, _leds: PlayerLeds) -> bool {
+        false
+    }
+    fn set_mute_led(&mut self, _mute: MuteLed) -> bool {
+        false
+    }
+    fn set_bt_latency(&mut self, latency: u8) {
+        self.latency = latency;
+    }
+    fn set_volume(&mut self, left: u8, right: u8, speaker: u8) -> bool {
+        self.volume_l = left;
+        self.volume_r = right;
+        self.volume_speaker = speaker;
+        self.volume_set = true;
+        true
     }
-    fn write_packet_usb(&self, f_write: &mut File) -> io::Result<()> {
-        let mut pkt = [0; 32];
-        pkt[4..11].copy_from_slice(&self.fill_packet());
+    fn audio_output(&self) -> Option<Vec<u8>> {
+        let mut output = vec![0; AUDIO_OUTPUT_LEN];
+        output[0] = self.flags();
+        output[3..23].copy_from_slice(&self.fill_packet());
+        Some(output)
+    }
+    fn report_usb(&self) -> Vec<u8> {
+        let mut pkt = vec![0; 32];
+        pkt[4..24].copy_from_slice(&self.fill_packet());
         pkt[0] = 0x05;
-        pkt[1] = 0x07;
-        let count = f_write.write(&pkt)?;
-        assert_eq!(count, 32);
-        f_write.flush()
+        pkt[1] = self.flags();
+        pkt
     }
 
-    fn write_packet_bt(&mut self, f_write: &mut File) -> io::Result<()> {
-        let mut pkt = [0; 78];
-        pkt[6..13].copy_from_slice(&self.fill_packet());
+    fn report_bt(&mut self) -> Vec<u8> {
+        let mut pkt = vec![0; 78];
+        pkt[6..26].copy_from_slice(&self.fill_packet());
         pkt[0] = 0x11;
         pkt[1] = 0xC0 | self.latency;
-        pkt[3] = 0x07; //magic
-        pkt[21] = self.volume_l;
-        pkt[22] = self.volume_r;
-        pkt[23] = 0x49; // magic
-        pkt[24] = self.volume_speaker;
-        pkt[25] = 0x85; //magic
+        pkt[3] = self.flags();
         let crc = calculate_checksum_bt(&pkt[0..74]);
         pkt[74..78].copy_from_slice(&crc);
-        let count = f_write.write(&pkt)?;
-        assert_eq!(count, 78);
-        f_write.flush()
+        pkt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_report() {
+        let mut dsc = DS4Controls::default();
+        dsc.set_rumble(200, 100);
+        dsc.set_color(255, 128, 0);
+        dsc.set_flash(50, 25);
+        let mut expected = vec![0; 32];
+        expected[..11].copy_from_slice(&[0x05, 0x07, 0, 0, 100, 200, 255, 128, 0, 50, 25]);
+        expected[19..24].copy_from_slice(&[0, 0, 0x49, 0, 0x85]);
+        assert_eq!(dsc.report_usb(), expected);
+    }
+
+    // CRC is calculated by zlib.crc32 of 0xA2 and report, as in old-servers/play.py
+    #[test]
+    fn bt_report() {
+        let mut dsc = DS4Controls::default();
+        dsc.set_rumble(200, 100);
+        dsc.set_color(255, 128, 0);
+        assert!(dsc.set_volume(40, 40, 80));
+        let report = dsc.report_bt();
+        assert_eq!(report.len(), 78);
+        assert_eq!(report[..4], [0x11, 0xC4, 0x00, 0xF7]);
+        assert_eq!(report[6..13], [100, 200, 255, 128, 0, 0, 0]);
+        assert_eq!(report[21..26], [40, 40, 0x49, 80, 0x85]);
+        assert!(report[26..74].iter().all(|&b| b == 0));
+        assert_eq!(report[74..], [0xC9, 0xCA, 0x78, 0x87]);
+        // output within audio report is laid out the same way
+        let output = dsc.audio_output().unwrap();
+        assert_eq!(output.len(), AUDIO_OUTPUT_LEN);
+        assert_eq!(output[..23], report[3..26]);
+        assert!(output[23..].iter().all(|&b| b == 0));
     }
 }
@@ -1,8 +1,74 @@
-use std::fs::File;
-use std::io;
-use std::io::Write;
+use std::time::Instant;
 
-use crate::common_output::{calculate_checksum_bt, Controls};
+use crate::common_input::{BatteryStatus, ChargingState};
+use crate::common_output::{
+    calculate_checksum_bt, Controls, MuteLed, PlayerLeds, Trigger, TriggerEffect, DEFAULT_COLOR,
+    FLASH_CHARGING_ERROR, FLASH_LOW_BATTERY, FLASH_NONE, LOW_BATTERY_LEVEL, TRIGGER_ZONES,
+};
+
+// Ticks of control thread between switches of charging animation
+const CHARGING_BLINK_TICKS: u8 = 5;
+
+// 3 bits of value for every zone from start to the end of travel
+fn trigger_zones(start: u8, value: u8) -> (u16, u32) {
+    let mut active: u16 = 0;
+    let mut values: u32 = 0;
+    for i in start..TRIGGER_ZONES {
+        active |= 1 << i;
+        values |= ((value - 1) as u32 & 0x07) << (3 * i);
+    }
+    (active, values)
+}
+
+// Effect block of output report, invalid effects are turned off
+fn trigger_effect_block(effect: TriggerEffect) -> [u8; 11] {
+    let mut block = [0; 11];
+    if !effect.is_valid() {
+        block[0] = 0x05;
+        return block;
+    }
+    match effect {
+        TriggerEffect::Off => block[0] = 0x05,
+        TriggerEffect::Resistance { start, strength } => {
+            let (active, values) = trigger_zones(start, strength);
+            block[0] = 0x21;
+            block[1..3].copy_from_slice(&active.to_le_bytes());
+            block[3..7].copy_from_slice(&values.to_le_bytes());
+        }
+        TriggerEffect::Weapon {
+            start,
+            end,
+            strength,
+        } => {
+            let zones: u16 = (1 << start) | (1 << end);
+            block[0] = 0x25;
+            block[1..3].copy_from_slice(&zones.to_le_bytes());
+            block[3] = strength - 1;
+        }
+        TriggerEffect::Vibration {
+            start,
+            amplitude,
+            frequency,
+        } => {
+            let (active, values) = trigger_zones(start, amplitude);
+            block[0] = 0x26;
+            block[1..3].copy_from_slice(&active.to_le_bytes());
+            block[3..7].copy_from_slice(&values.to_le_bytes());
+            block[9] = frequency;
+        }
+    }
+    block
+}
+
+fn get_player_led_from_number(number: u8) -> u8 {
+    match number {
+        1 => 0x04,
+        2 => 0x0A,
+        3 => 0x15,
+        4 => 0x1B,
+        _ => 0,
+    }
+}
 
 #[derive(Debug)]
 pub struct DSenseControls {
@@ -11,8 +77,18 @@ pub struct DSenseControls {
     red: u8,
     green: u8,
     blue: u8,
-    battery: u8,
+    battery: BatteryStatus,
     seq: u8,
+    ticks: u8,
+    blink: bool,
+    // DualSense has no hardware flash, so lightbar is switched off by control thread
+    flash: (u8, u8),
+    flash_start: Instant,
+    flash_dark: bool,
+    left_trigger: TriggerEffect,
+    right_trigger: TriggerEffect,
+    player_leds: PlayerLeds,
+    mute_led: MuteLed,
 }
 
 impl Default for DSenseControls {
@@ -20,11 +96,23 @@ impl Default for DSenseControls {
         Self {
             large: 0,
             small: 0,
-            red: 0,
-            green: 0,
-            blue: 255,
-            battery: 100,
+            red: DEFAULT_COLOR.0,
+            green: DEFAULT_COLOR.1,
+            blue: DEFAULT_COLOR.2,
+            battery: BatteryStatus {
+                level: 100,
+                ..Default::default()
+            },
             seq: 0,
+            ticks: 0,
+            blink: false,
+            flash: FLASH_NONE,
+            flash_start: Instant::now(),
+            flash_dark: false,
+            left_trigger: TriggerEffect::Off,
+            right_trigger: TriggerEffect::Off,
+            player_leds: PlayerLeds::Battery,
+            mute_led: MuteLed::Off,
         }
     }
 }
@@ -49,19 +137,65 @@ fn get_player_led_from_battery(battery: u8) -> u8 {
 }
 
 impl DSenseControls {
+    fn player_led(&self) -> u8 {
+        if let PlayerLeds::Player(number) = self.player_leds {
+            return get_player_led_from_number(number);
+        }
+        match self.battery.state {
+            ChargingState::Discharging => get_player_led_from_battery(self.battery.level),
+            // blink next segment of the gauge
+            ChargingState::Charging if self.blink => {
+                get_player_led_from_battery(self.battery.level.saturating_add(20))
+            }
+            ChargingState::Charging => get_player_led_from_battery(self.battery.level),
+            ChargingState::Full => 0x1F,
+            // two outer LEDs
+            ChargingState::Error => 0x11,
+        }
+    }
+
+    // Color and flash, which should be shown, battery warnings have priority over client
+    fn lightbar(&self) -> ((u8, u8, u8), (u8, u8)) {
+        match self.battery.state {
+            ChargingState::Error => ((255, 0, 0), FLASH_CHARGING_ERROR),
+            ChargingState::Discharging if self.battery.level <= LOW_BATTERY_LEVEL => {
+                ((255, 0, 0), FLASH_LOW_BATTERY)
+            }
+            _ => ((self.red, self.green, self.blue), self.flash),
+        }
+    }
+
+    fn is_flash_dark(&self) -> bool {
+        let (bright, dark) = self.lightbar().1;
+        if bright == 0 || dark == 0 {
+            return false;
+        }
+        // flash times are in 10ms units
+        let period = (bright as u128 + dark as u128) * 10;
+        self.flash_start.elapsed().as_millis() % period >= bright as u128 * 10
+    }
+
     fn fill_packet(&self) -> [u8; 47] {
         let mut pkt = [0; 47];
         pkt[0] = 0x0F;
         pkt[1] = 0x55;
         pkt[2] = self.small;
         pkt[3] = self.large;
+        pkt[8] = self.mute_led as u8;
+        pkt[10..21].copy_from_slice(&trigger_effect_block(self.right_trigger));
+        pkt[21..32].copy_from_slice(&trigger_effect_block(self.left_trigger));
         pkt[38] = 0x05;
         //pkt[41] = 0x02;
         pkt[42] = 0x02;
-        pkt[43] = get_player_led_from_battery(self.battery);
-        pkt[44] = self.red;
-        pkt[45] = self.green;
-        pkt[46] = self.blue;
+        pkt[43] = self.player_led();
+        let (red, green, blue) = if self.flash_dark {
+            (0, 0, 0)
+        } else {
+            self.lightbar().0
+        };
+        pkt[44] = red;
+        pkt[45] = green;
+        pkt[46] = blue;
         pkt
     }
 }
@@ -76,20 +210,63 @@ impl Controls for DSenseControls {
         self.large = large;
         self.small = small;
     }
-    fn set_battery(&mut self, level: u8) {
-        self.battery = level;
+    fn set_battery(&mut self, status: BatteryStatus) {
+        self.battery = status;
+    }
+    fn set_flash(&mut self, bright: u8, dark: u8) {
+        self.flash = (bright, dark);
+        self.flash_start = Instant::now();
+        self.flash_dark = false;
+    }
+    fn set_trigger_effect(&mut self, trigger: Trigger, effect: TriggerEffect) -> bool {
+        match trigger {
+            Trigger::Left => self.left_trigger = effect,
+            Trigger::Right => self.right_trigger = effect,
+        }
+        true
+    }
+    fn set_player_leds(&mut self, leds: PlayerLeds) -> bool {
+        self.player_leds = leds;
+        true
     }
-    fn write_packet_usb(&self, f_write: &mut File) -> io::Result<()> {
-        let mut pkt = [0; 63];
+    fn set_mute_led(&mut self, mute: MuteLed) -> bool {
+        self.mute_led = mute;
+        true
+    }
+    fn set_volume(&mut self, _left: u8, _right: u8, _speaker: u8) -> bool {
+        false
+    }
+    fn tick(&mut self) -> bool {
+        let mut changed = false;
+        // charging animation is shown only by battery gauge
+        if self.battery.state == ChargingState::Charging && self.player_leds == PlayerLeds::Battery
+        {
+            self.ticks += 1;
+            if self.ticks >= CHARGING_BLINK_TICKS {
+                self.ticks = 0;
+                self.blink = !self.blink;
+                changed = true;
+            }
+        }
+        let flash_dark = self.is_flash_dark();
+        if flash_dark != self.flash_dark {
+            self.flash_dark = flash_dark;
+            changed = true;
+        }
+        changed
+    }
+    fn is_flash_emulated(&self) -> bool {
+        true
+    }
+    fn report_usb(&self) -> Vec<u8> {
+        let mut pkt = vec![0; 63];
         pkt[1..48].copy_from_slice(&self.fill_packet());
         pkt[0] = 0x02;
-        let count = f_write.write(&pkt)?;
-        assert_eq!(count, 63);
-        f_write.flush()
+        pkt
     }
 
-    fn write_packet_bt(&mut self, f_write: &mut File) -> io::Result<()> {
-        let mut pkt = [0; 78];
+    fn report_bt(&mut self) -> Vec<u8> {
+        let mut pkt = vec![0; 78];
         pkt[3..50].copy_from_slice(&self.fill_packet());
         pkt[0] = 0x31;
         pkt[1] = self.seq << 4;
@@ -100,8 +277,119 @@ impl Controls for DSenseControls {
         }
         let crc = calculate_checksum_bt(&pkt[0..74]);
         pkt[74..78].copy_from_slice(&crc);
-        let count = f_write.write(&pkt)?;
-        assert_eq!(count, 78);
-        f_write.flush()
+        pkt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resistance_effect() {
+        let effect = TriggerEffect::Resistance {
+            start: 7,
+            strength: 8,
+        };
+        assert_eq!(
+            trigger_effect_block(effect),
+            [0x21, 0x80, 0x03, 0x00, 0x00, 0xE0, 0x3F, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn weapon_effect() {
+        let effect = TriggerEffect::Weapon {
+            start: 2,
+            end: 5,
+            strength: 4,
+        };
+        assert_eq!(
+            trigger_effect_block(effect),
+            [0x25, 0x24, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn vibration_effect() {
+        let effect = TriggerEffect::Vibration {
+            start: 9,
+            amplitude: 2,
+            frequency: 40,
+        };
+        assert_eq!(
+            trigger_effect_block(effect),
+            [0x26, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x28, 0x00]
+        );
+    }
+
+    #[test]
+    fn player_and_mute_leds() {
+        let mut dsc = DSenseControls::default();
+        dsc.set_battery(BatteryStatus {
+            level: 55,
+            ..Default::default()
+        });
+        assert_eq!(dsc.fill_packet()[43], 0x15);
+        dsc.set_player_leds(PlayerLeds::Player(2));
+        dsc.set_mute_led(MuteLed::Pulse);
+        let pkt = dsc.fill_packet();
+        assert_eq!(pkt[43], 0x0A);
+        assert_eq!(pkt[8], 0x02);
+        assert!(!PlayerLeds::Player(5).is_valid());
+    }
+
+    fn expected_common() -> Vec<u8> {
+        let mut common = vec![0; 47];
+        common[..4].copy_from_slice(&[0x0F, 0x55, 100, 200]);
+        common[10] = 0x05;
+        common[21] = 0x05;
+        common[38] = 0x05;
+        common[42..].copy_from_slice(&[0x02, 0x1F, 0, 0, 255]);
+        common
+    }
+
+    #[test]
+    fn usb_report() {
+        let mut dsc = DSenseControls::default();
+        dsc.set_rumble(200, 100);
+        let report = dsc.report_usb();
+        assert_eq!(report.len(), 63);
+        assert_eq!(report[0], 0x02);
+        assert_eq!(report[1..48], expected_common()[..]);
+        assert!(report[48..].iter().all(|&b| b == 0));
+    }
+
+    // CRCs are calculated by zlib.crc32 of 0xA2 and report, as in old-servers/play.py
+    #[test]
+    fn bt_report_sequence() {
+        let mut dsc = DSenseControls::default();
+        dsc.set_rumble(200, 100);
+        let report = dsc.report_bt();
+        assert_eq!(report.len(), 78);
+        assert_eq!(report[..3], [0x31, 0x00, 0x10]);
+        assert_eq!(report[3..50], expected_common()[..]);
+        assert_eq!(report[74..], [0xEC, 0x4E, 0x6B, 0x70]);
+        let report = dsc.report_bt();
+        assert_eq!(report[1], 0x10);
+        assert_eq!(report[74..], [0x45, 0xBA, 0x57, 0x0C]);
+        for _ in 0..14 {
+            dsc.report_bt();
+        }
+        assert_eq!(dsc.report_bt()[1], 0x00);
+    }
+
+    #[test]
+    fn invalid_effect_is_off() {
+        let effect = TriggerEffect::Weapon {
+            start: 6,
+            end: 6,
+            strength: 4,
+        };
+        assert!(!effect.is_valid());
+        assert_eq!(
+            trigger_effect_block(effect),
+            trigger_effect_block(TriggerEffect::Off)
+        );
     }
 }
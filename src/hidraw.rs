@@ -0,0 +1,142 @@
+// Device layer: everything which touches hidraw files, reports themselves
+// are parsed and encoded by input_* and controls_* modules
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::common_input::{Packet, PacketError};
+use crate::udevmon::DSType;
+
+// Larger than any input report, so reports of wrong size are not truncated to the right one
+const MAX_REPORT_LEN: usize = 128;
+
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Input and output streams of gamepad, which are either hidraw files or virtual ones.
+// Every read returns one whole report and every write takes one whole report.
+// Virtual readers could wait for input for long, they fail with WouldBlock after
+// READ_TIMEOUT, so input thread could check, if it should stop.
+pub type DeviceReader = Box<dyn Read + Send>;
+pub type DeviceWriter = Box<dyn Write + Send>;
+
+// Opens gamepad and returns streams for input thread and control thread
+pub fn open_gamepad(path: &str) -> io::Result<(DeviceReader, DeviceWriter)> {
+    let f_write = OpenOptions::new().read(true).write(true).open(path)?;
+    let f_read = f_write.try_clone()?;
+    Ok((Box::new(f_read), Box::new(f_write)))
+}
+
+// Every read of hidraw returns one whole report
+pub fn read_packet<T: Packet>(f_read: &mut impl Read, packet: &mut T) -> Result<(), PacketError> {
+    let mut buf = [0; MAX_REPORT_LEN];
+    let count = f_read.read(&mut buf)?;
+    packet.parse(&buf[..count])
+}
+
+pub fn write_report(f_write: &mut impl Write, report: &[u8]) -> io::Result<()> {
+    let count = f_write.write(report)?;
+    if count != report.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            format!("only {} of {} bytes of report written", count, report.len()),
+        ));
+    }
+    f_write.flush()
+}
+
+// HIDIOCGFEATURE(len) from linux/hidraw.h, _IOC(_IOC_WRITE | _IOC_READ, 'H', 0x07, len)
+fn hidiocgfeature(len: usize) -> libc::Ioctl {
+    ((3 << 30) | (len << 16) | ((b'H' as usize) << 8) | 0x07) as libc::Ioctl
+}
+
+pub fn get_feature_report(f: &File, report_id: u8, buf: &mut [u8]) -> io::Result<usize> {
+    buf[0] = report_id;
+    let res = unsafe { libc::ioctl(f.as_raw_fd(), hidiocgfeature(buf.len()), buf.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res as usize)
+}
+
+// MAC is stored in reverse order in pairing info reports
+fn format_mac(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .rev()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn read_mac(f: &File, ds_type: DSType) -> io::Result<String> {
+    let mut buf = [0u8; 20];
+    let (report_id, size) = match ds_type {
+        // DS4 over BT has no pairing info report, MAC is taken from HID_UNIQ
+        DSType::DS4BT => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        DSType::DS4USB => (0x12, 16),
+        DSType::SenseBT | DSType::SenseUSB => (0x09, 20),
+    };
+    let count = get_feature_report(f, report_id, &mut buf[..size])?;
+    if count < 7 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(format_mac(&buf[1..7]))
+}
+
+fn read_firmware(f: &File, ds_type: DSType) -> io::Result<u32> {
+    let mut buf = [0u8; 64];
+    match ds_type {
+        DSType::DS4BT | DSType::DS4USB => {
+            let count = get_feature_report(f, 0xA3, &mut buf[..49])?;
+            if count < 43 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            Ok(u16::from_le_bytes([buf[41], buf[42]]) as u32)
+        }
+        DSType::SenseBT | DSType::SenseUSB => {
+            let count = get_feature_report(f, 0x20, &mut buf)?;
+            if count < 32 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            Ok(u32::from_le_bytes([buf[28], buf[29], buf[30], buf[31]]))
+        }
+    }
+}
+
+// Returns MAC address and firmware version, if they could be read
+pub fn read_identity(path: &str, ds_type: DSType) -> (Option<String>, Option<u32>) {
+    let f = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Error on opening {} for reading identity: {}", path, e);
+            return (None, None);
+        }
+    };
+    let mac = read_mac(&f, ds_type)
+        .map_err(|e| warn!("Error on reading MAC of {}: {}", path, e))
+        .ok();
+    let firmware = read_firmware(&f, ds_type)
+        .map_err(|e| warn!("Error on reading firmware version of {}: {}", path, e))
+        .ok();
+    (mac, firmware)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_number() {
+        assert_eq!(hidiocgfeature(16) as u64, 0xC0104807);
+    }
+
+    #[test]
+    fn mac_is_reversed() {
+        let report = [0x09, 0x56, 0x34, 0x12, 0xEF, 0xCD, 0xAB];
+        assert_eq!(format_mac(&report[1..7]), "ab:cd:ef:12:34:56");
+    }
+}
@@ -1,8 +1,7 @@
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
-
-use crate::common_input::{DS4PacketInner, Packet, PACKET_LEN_BT, PACKET_LEN_USB};
+use crate::common_input::{
+    is_valid_checksum_bt, BatteryStatus, ChargingState, ControllerState, DS4PacketInner, Packet,
+    PacketError, TouchPoint, PACKET_LEN_BT, PACKET_LEN_USB,
+};
 
 pub struct DS4PacketBT {
     inner: [u8; PACKET_LEN_BT],
@@ -11,6 +10,51 @@ pub struct DS4PacketUSB {
     inner: DS4PacketInner,
 }
 
+// data is the common part of DS4 input report, without report ID
+// (and without two extra bytes on BT), so offsets are the same for USB and BT
+fn decode_common(data: &[u8]) -> ControllerState {
+    let mut state = ControllerState {
+        lx: data[0],
+        ly: data[1],
+        rx: data[2],
+        ry: data[3],
+        l2: data[7],
+        r2: data[8],
+        counter: data[6] >> 2,
+        // DS4 timestamp is in 5.33us units
+        timestamp: u32::from(u16::from_le_bytes([data[9], data[10]])) * 16,
+        temperature: data[11],
+        touch: [
+            TouchPoint::from_bytes(&data[34..38]),
+            TouchPoint::from_bytes(&data[38..42]),
+        ],
+        ..Default::default()
+    };
+    state.set_buttons0(data[4]);
+    state.set_buttons1(data[5]);
+    state.buttons.ps = data[6] & 0x01 != 0;
+    state.buttons.touchpad = data[6] & 0x02 != 0;
+    state.set_motion(&data[12..24]);
+    // status: lower nibble is battery level (0-10, 11 is full when on cable,
+    // everything above is error), 0x10 is cable, 0x20 is headphones, 0x40 is microphone
+    let status = data[29];
+    let level = status & 0xF;
+    let cable = status & 0x10 != 0;
+    state.battery = BatteryStatus {
+        level: level.min(10) * 10,
+        state: match (cable, level) {
+            (false, _) => ChargingState::Discharging,
+            (true, 0..=10) => ChargingState::Charging,
+            (true, 11) => ChargingState::Full,
+            (true, _) => ChargingState::Error,
+        },
+        cable,
+    };
+    state.headphones = status & 0x20 != 0;
+    state.microphone = status & 0x40 != 0;
+    state
+}
+
 impl Default for DS4PacketBT {
     fn default() -> Self {
         Self {
@@ -20,14 +64,22 @@ impl Default for DS4PacketBT {
 }
 
 impl Packet for DS4PacketBT {
-    fn read(&mut self, f: &mut File) -> io::Result<()> {
-        let count = f.read(&mut self.inner)?;
-        assert_eq!(count, self.get_size());
-        assert!(self.is_valid());
+    fn parse(&mut self, buf: &[u8]) -> Result<(), PacketError> {
+        let size = self.get_size();
+        if buf.len() < size {
+            return Err(PacketError::ShortRead(buf.len()));
+        }
+        self.inner.copy_from_slice(&buf[..size]);
+        if !self.is_valid() {
+            return Err(PacketError::WrongReportId(self.inner[0]));
+        }
+        if !is_valid_checksum_bt(&self.inner) {
+            return Err(PacketError::BadCrc);
+        }
         Ok(())
     }
-    fn battery_capacity(&self) -> u8 {
-        (self.inner[32] & 0xF) * 10
+    fn decode(&self) -> ControllerState {
+        decode_common(&self.inner[3..])
     }
     fn to_ds4_packet(&self) -> DS4PacketInner {
         let mut res: DS4PacketInner = [0; PACKET_LEN_USB];
@@ -51,14 +103,19 @@ impl Default for DS4PacketUSB {
 }
 
 impl Packet for DS4PacketUSB {
-    fn read(&mut self, f: &mut File) -> io::Result<()> {
-        let count = f.read(&mut self.inner)?;
-        assert_eq!(count, self.get_size());
-        assert!(self.is_valid());
+    fn parse(&mut self, buf: &[u8]) -> Result<(), PacketError> {
+        let size = self.get_size();
+        if buf.len() < size {
+            return Err(PacketError::ShortRead(buf.len()));
+        }
+        self.inner.copy_from_slice(&buf[..size]);
+        if !self.is_valid() {
+            return Err(PacketError::WrongReportId(self.inner[0]));
+        }
         Ok(())
     }
-    fn battery_capacity(&self) -> u8 {
-        (self.inner[30] & 0xF) * 10
+    fn decode(&self) -> ControllerState {
+        decode_common(&self.inner[1..])
     }
     fn to_ds4_packet(&self) -> DS4PacketInner {
         self.inner
@@ -70,3 +127,40 @@ impl Packet for DS4PacketUSB {
         PACKET_LEN_USB
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BT report: sticks near center, d-pad neutral, battery 80%
+    const REPORT_BT: [u8; PACKET_LEN_BT] = [
+        0x11, 0xC0, 0x00, 0x7F, 0x80, 0x83, 0x7D, 0x08, 0x00, 0x54, 0x00, 0x00, 0x2C, 0xB5, 0x17,
+        0x03, 0x00, 0xFD, 0xFF, 0x01, 0x00, 0x6A, 0x00, 0x20, 0x20, 0x3A, 0x05, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x08, 0x00, 0x00, 0x01, 0x4E, 0x80, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0B,
+        0xEE, 0xBF, 0x45,
+    ];
+
+    #[test]
+    fn bt_checksum() {
+        assert!(is_valid_checksum_bt(&REPORT_BT));
+        for i in [0, 4, 40, 76] {
+            let mut report = REPORT_BT;
+            report[i] ^= 0x01;
+            assert!(!is_valid_checksum_bt(&report));
+        }
+    }
+
+    #[test]
+    fn bt_decode() {
+        let state = DS4PacketBT { inner: REPORT_BT }.decode();
+        assert_eq!(
+            (state.lx, state.ly, state.rx, state.ry),
+            (0x7F, 0x80, 0x83, 0x7D)
+        );
+        assert_eq!(state.battery.level, 80);
+        assert_eq!(state.battery.state, ChargingState::Discharging);
+        assert!(!state.touch[0].active);
+    }
+}
@@ -1,8 +1,7 @@
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
-
-use crate::common_input::{DS4PacketInner, Packet, PACKET_LEN_BT, PACKET_LEN_USB};
+use crate::common_input::{
+    is_valid_checksum_bt, BatteryStatus, ChargingState, ControllerState, DS4PacketInner, Packet,
+    PacketError, TouchPoint, PACKET_LEN_BT, PACKET_LEN_USB,
+};
 
 pub struct DSensePacketBT {
     inner: [u8; PACKET_LEN_BT],
@@ -11,6 +10,62 @@ pub struct DSensePacketUSB {
     inner: [u8; PACKET_LEN_USB],
 }
 
+// Touchpad heights, width is 1920 on both
+const DS4_TOUCHPAD_HEIGHT: u32 = 942;
+const DSENSE_TOUCHPAD_HEIGHT: u32 = 1080;
+
+fn decode_touch_point(data: &[u8]) -> TouchPoint {
+    let mut point = TouchPoint::from_bytes(data);
+    point.y = (u32::from(point.y) * DS4_TOUCHPAD_HEIGHT / DSENSE_TOUCHPAD_HEIGHT) as u16;
+    point
+}
+
+// data is the common part of DualSense input report, without report ID
+// (and without sequence tag on BT), so offsets are the same for USB and BT
+fn decode_common(data: &[u8]) -> ControllerState {
+    let mut state = ControllerState {
+        lx: data[0],
+        ly: data[1],
+        rx: data[2],
+        ry: data[3],
+        l2: data[4],
+        r2: data[5],
+        counter: data[6],
+        timestamp: u32::from_le_bytes([data[27], data[28], data[29], data[30]]),
+        temperature: data[31],
+        touch: [
+            decode_touch_point(&data[32..36]),
+            decode_touch_point(&data[36..40]),
+        ],
+        ..Default::default()
+    };
+    state.set_buttons0(data[7]);
+    state.set_buttons1(data[8]);
+    state.buttons.ps = data[9] & 0x01 != 0;
+    state.buttons.touchpad = data[9] & 0x02 != 0;
+    // gyro and accelerometer, both controllers use the same resolution
+    state.set_motion(&data[15..27]);
+    // status: lower nibble is battery level, upper is charging state
+    let (status, plugged) = (data[52], data[53]);
+    let charging_state = match status >> 4 {
+        0x00 => ChargingState::Discharging,
+        0x01 => ChargingState::Charging,
+        0x02 => ChargingState::Full,
+        _ => ChargingState::Error,
+    };
+    state.battery = BatteryStatus {
+        level: match charging_state {
+            ChargingState::Full => 100,
+            _ => (status & 0xF).min(10) * 10,
+        },
+        state: charging_state,
+        cable: charging_state != ChargingState::Discharging || plugged & 0x10 != 0,
+    };
+    state.headphones = plugged & 0x01 != 0;
+    state.microphone = plugged & 0x02 != 0;
+    state
+}
+
 impl Default for DSensePacketBT {
     fn default() -> Self {
         Self {
@@ -20,30 +75,25 @@ impl Default for DSensePacketBT {
 }
 
 impl Packet for DSensePacketBT {
-    fn read(&mut self, f: &mut File) -> io::Result<()> {
-        let count = f.read(&mut self.inner)?;
-        assert_eq!(count, self.get_size());
-        assert!(self.is_valid());
+    fn parse(&mut self, buf: &[u8]) -> Result<(), PacketError> {
+        let size = self.get_size();
+        if buf.len() < size {
+            return Err(PacketError::ShortRead(buf.len()));
+        }
+        self.inner.copy_from_slice(&buf[..size]);
+        if !self.is_valid() {
+            return Err(PacketError::WrongReportId(self.inner[0]));
+        }
+        if !is_valid_checksum_bt(&self.inner) {
+            return Err(PacketError::BadCrc);
+        }
         Ok(())
     }
-    fn battery_capacity(&self) -> u8 {
-        match self.inner[54] >> 4 {
-            0x00 | 0x01 => (self.inner[54] & 0xF) * 10,
-            0x02 => 100,
-            _ => panic!("Bad status of charging"),
-        }
+    fn decode(&self) -> ControllerState {
+        decode_common(&self.inner[2..])
     }
     fn to_ds4_packet(&self) -> DS4PacketInner {
-        let mut new_packet: DS4PacketInner = [0; PACKET_LEN_USB];
-        new_packet[1] = self.inner[2];
-        new_packet[2] = self.inner[3];
-        new_packet[3] = self.inner[4];
-        new_packet[4] = self.inner[5];
-        new_packet[5] = self.inner[9];
-        new_packet[6] = self.inner[10];
-        new_packet[8] = self.inner[6];
-        new_packet[9] = self.inner[7];
-        new_packet
+        self.decode().to_ds4_packet()
     }
     fn is_valid(&self) -> bool {
         self.inner[0] == 0x31
@@ -62,32 +112,24 @@ impl Default for DSensePacketUSB {
 }
 
 impl Packet for DSensePacketUSB {
-    fn read(&mut self, f: &mut File) -> io::Result<()> {
-        let count = f.read(&mut self.inner)?;
-        assert_eq!(count, self.get_size());
-        assert!(self.is_valid());
+    fn parse(&mut self, buf: &[u8]) -> Result<(), PacketError> {
+        let size = self.get_size();
+        if buf.len() < size {
+            return Err(PacketError::ShortRead(buf.len()));
+        }
+        self.inner.copy_from_slice(&buf[..size]);
+        if !self.is_valid() {
+            return Err(PacketError::WrongReportId(self.inner[0]));
+        }
         Ok(())
     }
 
-    fn battery_capacity(&self) -> u8 {
-        match self.inner[53] >> 4 {
-            0x00 | 0x01 => (self.inner[53] & 0xF) * 10,
-            0x02 => 100,
-            _ => panic!("Bad status of charging"),
-        }
+    fn decode(&self) -> ControllerState {
+        decode_common(&self.inner[1..])
     }
 
     fn to_ds4_packet(&self) -> DS4PacketInner {
-        let mut new_packet: DS4PacketInner = [0; PACKET_LEN_USB];
-        new_packet[1] = self.inner[1];
-        new_packet[2] = self.inner[2];
-        new_packet[3] = self.inner[3];
-        new_packet[4] = self.inner[4];
-        new_packet[5] = self.inner[8];
-        new_packet[6] = self.inner[9];
-        new_packet[8] = self.inner[5];
-        new_packet[9] = self.inner[6];
-        new_packet
+        self.decode().to_ds4_packet()
     }
     fn is_valid(&self) -> bool {
         self.inner[0] == 0x01
@@ -96,3 +138,92 @@ impl Packet for DSensePacketUSB {
         PACKET_LEN_USB
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // USB report: sticks moved, L2 half, R2 full, cross + R1 + R2 + PS + touchpad,
+    // charging at 50% with headphones, one finger on touchpad
+    const REPORT_USB: [u8; PACKET_LEN_USB] = [
+        0x01, 0x80, 0x7F, 0x81, 0x7E, 0x10, 0xFF, 0x2A, 0x28, 0x0A, 0x07, 0x00, 0x1B, 0x5A, 0x3C,
+        0xA1, 0xFE, 0xFF, 0x02, 0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x50, 0x34,
+        0x12, 0x00, 0x1A, 0x05, 0xC0, 0xC3, 0x21, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const EXPECTED_DS4: [u8; PACKET_LEN_USB] = [
+        0x00, 0x80, 0x7F, 0x81, 0x7E, 0x28, 0x0A, 0xAB, 0x10, 0xFF, 0x45, 0x23, 0x1A, 0xFE, 0xFF,
+        0x02, 0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x35, 0x00, 0x00, 0x01, 0x2A, 0x05, 0xC0, 0x73, 0x1D, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // the same state, received over BT
+    const REPORT_BT: [u8; PACKET_LEN_BT] = [
+        0x31, 0x10, 0x80, 0x7F, 0x81, 0x7E, 0x10, 0xFF, 0x2A, 0x28, 0x0A, 0x07, 0x00, 0x1B, 0x5A,
+        0x3C, 0xA1, 0xFE, 0xFF, 0x02, 0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x50,
+        0x34, 0x12, 0x00, 0x1A, 0x05, 0xC0, 0xC3, 0x21, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x89,
+        0x42, 0xF1, 0x99,
+    ];
+
+    #[test]
+    fn usb_to_ds4() {
+        let packet = DSensePacketUSB { inner: REPORT_USB };
+        assert_eq!(packet.to_ds4_packet(), EXPECTED_DS4);
+    }
+
+    #[test]
+    fn bt_to_ds4() {
+        let packet = DSensePacketBT { inner: REPORT_BT };
+        assert_eq!(packet.to_ds4_packet(), EXPECTED_DS4);
+    }
+
+    #[test]
+    fn parse_reports() {
+        let mut packet = DSensePacketBT::default();
+        packet.parse(&REPORT_BT).unwrap();
+        assert_eq!(packet.to_ds4_packet(), EXPECTED_DS4);
+        let mut report = REPORT_BT;
+        report[9] ^= 0x20;
+        assert!(matches!(packet.parse(&report), Err(PacketError::BadCrc)));
+        assert!(matches!(
+            packet.parse(&REPORT_BT[..64]),
+            Err(PacketError::ShortRead(64))
+        ));
+        let mut packet = DSensePacketUSB::default();
+        assert!(matches!(
+            packet.parse(&REPORT_BT[..64]),
+            Err(PacketError::WrongReportId(0x31))
+        ));
+    }
+
+    #[test]
+    fn bt_checksum() {
+        assert!(is_valid_checksum_bt(&REPORT_BT));
+        let mut report = REPORT_BT;
+        report[9] ^= 0x20;
+        assert!(!is_valid_checksum_bt(&report));
+    }
+
+    #[test]
+    fn full_battery_on_cable() {
+        let mut report = REPORT_USB;
+        report[53] = 0x2A;
+        report[54] = 0x00;
+        let packet = DSensePacketUSB { inner: report };
+        assert_eq!(packet.to_ds4_packet()[30], 0x1B);
+    }
+
+    #[test]
+    fn touch_release() {
+        let mut report = REPORT_USB;
+        report[33] = 0x85;
+        let packet = DSensePacketUSB { inner: report };
+        assert_eq!(packet.to_ds4_packet()[35..39], [0x85, 0xC0, 0x73, 0x1D]);
+    }
+}
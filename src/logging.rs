@@ -0,0 +1,243 @@
+// Logger for log facade, which writes either to stderr or natively to journald.
+// Targets are module names ("main" for crate root), so levels could be set per module.
+// Context of message (client, controller, slot) is given as key-values:
+//   info!(client:% = src, controller = sysname.as_str(); "Client connected");
+// and journald gets them as CLIENT, CONTROLLER and SLOT fields, with THREAD name,
+// which also tells client and slot for input and control threads.
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::io::Write as _;
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use std::thread;
+
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use parking_lot::RwLock;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "ds4net";
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Stderr,
+    Journald,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filters {
+    pub level: LevelFilter,
+    // module name and its level, overriding default one
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+impl Filters {
+    fn enabled(&self, module: &str, level: log::Level) -> bool {
+        level <= *self.modules.get(module).unwrap_or(&self.level)
+    }
+
+    // Maximal level of all modules, messages above it are skipped by log macros
+    fn max_level(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.level, Ord::max)
+    }
+}
+
+struct Logger {
+    filters: RwLock<Filters>,
+    // not connected, if output is stderr
+    journal: RwLock<Option<UnixDatagram>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+fn module_name(target: &str) -> &str {
+    match target.strip_prefix(CRATE_PREFIX) {
+        Some(module) => module,
+        None if target == env!("CARGO_CRATE_NAME") => "main",
+        None => target,
+    }
+}
+
+// Context of record: thread name and key-values
+#[derive(Default)]
+struct Fields {
+    thread: Option<String>,
+    values: Vec<(String, String)>,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.values.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn priority(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+// Field of journald native protocol, values with newlines are prefixed with length
+fn push_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+fn journal_field_name(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn encode_journal(level: log::Level, module: &str, message: &str, fields: &Fields) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_field(&mut buf, "PRIORITY", &priority(level).to_string());
+    push_field(&mut buf, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    push_field(&mut buf, "MODULE", module);
+    if let Some(thread) = &fields.thread {
+        push_field(&mut buf, "THREAD", thread);
+    }
+    push_field(&mut buf, "MESSAGE", message);
+    for (key, value) in &fields.values {
+        push_field(&mut buf, &journal_field_name(key), value);
+    }
+    buf
+}
+
+fn format_line(level: log::Level, module: &str, message: &str, fields: &Fields) -> String {
+    let mut line = match &fields.thread {
+        Some(thread) => format!("[{} {} {}] {}", level, module, thread, message),
+        None => format!("[{} {}] {}", level, module, message),
+    };
+    for (key, value) in &fields.values {
+        let _ = write!(line, " {}={}", key, value);
+    }
+    line
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filters
+            .read()
+            .enabled(module_name(metadata.target()), metadata.level())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let module = module_name(record.target());
+        let message = record.args().to_string();
+        let mut fields = Fields {
+            thread: thread::current().name().map(String::from),
+            ..Default::default()
+        };
+        let _ = record.key_values().visit(&mut fields);
+        if let Some(journal) = self.journal.read().as_ref() {
+            let datagram = encode_journal(record.level(), module, &message, &fields);
+            if journal.send(&datagram).is_ok() {
+                return;
+            }
+        }
+        let line = format_line(record.level(), module, &message, &fields);
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+fn connect_journal(output: Output) -> Option<UnixDatagram> {
+    if output != Output::Journald {
+        return None;
+    }
+    let journal = UnixDatagram::unbound().and_then(|socket| {
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(socket)
+    });
+    match journal {
+        Ok(journal) => Some(journal),
+        Err(e) => {
+            let _ = writeln!(
+                io::stderr(),
+                "Could not connect to journald, logging to stderr: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Installs logger on first call, and changes its filters and output on next calls
+pub fn apply(filters: Filters, output: Output) {
+    let max_level = filters.max_level();
+    let journal = connect_journal(output);
+    match LOGGER.get() {
+        Some(logger) => {
+            *logger.filters.write() = filters;
+            *logger.journal.write() = journal;
+        }
+        None => {
+            let logger = LOGGER.get_or_init(|| Logger {
+                filters: RwLock::new(filters),
+                journal: RwLock::new(journal),
+            });
+            let _ = log::set_logger(logger);
+        }
+    }
+    log::set_max_level(max_level);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_filters() {
+        let filters = Filters {
+            level: LevelFilter::Info,
+            modules: BTreeMap::from([("udevmon".to_string(), LevelFilter::Debug)]),
+        };
+        assert_eq!(module_name(CRATE_PREFIX.trim_end_matches(':')), "main");
+        assert_eq!(module_name(&format!("{}udevmon", CRATE_PREFIX)), "udevmon");
+        assert!(filters.enabled("udevmon", log::Level::Debug));
+        assert!(!filters.enabled("main", log::Level::Debug));
+        assert_eq!(filters.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn journal_datagram() {
+        let fields = Fields {
+            thread: Some("udev".to_string()),
+            values: vec![("client".to_string(), "[::1]:5000".to_string())],
+        };
+        let datagram = encode_journal(log::Level::Warn, "main", "two\nlines", &fields);
+        let mut expected =
+            b"PRIORITY=4\nSYSLOG_IDENTIFIER=ds4net\nMODULE=main\nTHREAD=udev\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nCLIENT=[::1]:5000\n");
+        assert_eq!(datagram, expected);
+        assert_eq!(
+            format_line(log::Level::Warn, "main", "Hello", &fields),
+            "[WARN main udev] Hello client=[::1]:5000"
+        );
+    }
+}
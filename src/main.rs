@@ -1,126 +1,382 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
 use std::io;
-use std::net::SocketAddr;
 use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
 use std::thread;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
-use parking_lot::RwLock;
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
 use signal_hook::consts::signal::*;
 
+mod audio_ds4;
+mod capture;
 mod common_input;
 mod common_output;
+mod config;
 mod controls_ds4;
 mod controls_dsense;
+mod hidraw;
 mod input_ds4;
 mod input_dsense;
+mod logging;
+mod protocol;
+mod replay;
 mod udevmon;
+mod virtual_gamepad;
 
-use common_input::{DS4PacketInner, Packet, PACKET_LEN_USB};
-use common_output::Controls;
+use audio_ds4::AudioStream;
+use common_input::{BatteryStatus, DS4PacketInner, Packet, PacketError, PACKET_LEN_USB};
+use common_output::{
+    Controls, MuteLed, PlayerLeds, Trigger, TriggerEffect, FLASH_NONE, FLASH_WAITING,
+};
+use config::{Args, Config, OutputSettings};
 use controls_ds4::DS4Controls;
 use controls_dsense::DSenseControls;
+use hidraw::{DeviceReader, DeviceWriter};
 use input_ds4::{DS4PacketBT, DS4PacketUSB};
 use input_dsense::{DSensePacketBT, DSensePacketUSB};
-use udevmon::{DSType, Gamepads};
+use protocol::{
+    ErrorCode, Request, Selector, KEEPALIVE_TIMEOUT, LEGACY_TIMEOUT, MAX_SLOTS, PROTOCOL_VERSION,
+};
+use udevmon::{Backend, DSGamepad, DSType, Gamepads, IdleOutput};
+
+// Control thread checks animations and stop flags at least this often
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+// Output reports are not written more often, so rumble floods do not saturate BT
+const MIN_WRITE_INTERVAL: Duration = Duration::from_millis(20);
+// Output report is written again after this time, even if nothing changed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Controller attached to client
+struct Slot {
+    sender: Sender<ControlType>,
+    stop: Arc<AtomicBool>,
+    sysname: String,
+    ds_type: DSType,
+    // control thread writes neutral report to gamepad, before it stops
+    control: JoinHandle<()>,
+    input: JoinHandle<()>,
+}
+
+struct Client {
+    slots: Vec<Option<Slot>>,
+    last_seen: Instant,
+    // only clients connected with hello are sending keepalives,
+    // could use several slots and get input reports tagged with slot
+    handshake: bool,
+    // outputs, requested in hello, legacy clients have all of them
+    outputs: u8,
+    // legacy client sent keepalive, so it is expected to go on sending them
+    keepalives: bool,
+}
 
-type Clients = HashMap<SocketAddr, Sender<ControlType>>;
-type SendFunc =
-    fn(SocketAddr, UdpSocket, File, Arc<AtomicBool>, Arc<AtomicBool>, Sender<ControlType>);
-type ControlFunc = fn(File, Receiver<ControlType>, Arc<AtomicBool>, Arc<AtomicBool>, bool);
+type Clients = HashMap<SocketAddr, Client>;
+// MACs of gamepads, which were used in slots by client from this address,
+// port is not remembered, as client gets new one after restart
+type KnownMacs = HashMap<(IpAddr, u8), String>;
+type SendFunc = fn(
+    SocketAddr,
+    Option<u8>,
+    UdpSocket,
+    DeviceReader,
+    Arc<AtomicBool>,
+    Arc<AtomicBool>,
+    Sender<ControlType>,
+    Arc<AtomicU64>,
+    Arc<Claim>,
+);
+type ControlFunc = fn(
+    DeviceWriter,
+    Receiver<ControlType>,
+    Arc<AtomicBool>,
+    Arc<AtomicBool>,
+    bool,
+    OutputSettings,
+    Arc<Claim>,
+);
 
 pub enum ControlType {
-    Rumble { large: u8, small: u8 },
-    Color { r: u8, g: u8, b: u8 },
-    Battery(u8),
+    Rumble {
+        large: u8,
+        small: u8,
+    },
+    Color {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    Flash {
+        bright: u8,
+        dark: u8,
+    },
+    // color shown after connect, idle color is back after deadline
+    ConnectColor {
+        r: u8,
+        g: u8,
+        b: u8,
+        until: Instant,
+    },
+    TriggerEffect {
+        trigger: Trigger,
+        effect: TriggerEffect,
+    },
+    PlayerLeds(PlayerLeds),
+    MuteLed(MuteLed),
+    Audio(Vec<u8>),
+    // configuration was reloaded
+    Settings(OutputSettings),
+    Volume {
+        left: u8,
+        right: u8,
+        speaker: u8,
+    },
+    Battery(BatteryStatus),
+}
+
+impl ControlType {
+    // Output, which gamepad should support for this control, internal controls need none
+    fn output(&self) -> u8 {
+        match self {
+            ControlType::Rumble { .. } => protocol::OUTPUT_RUMBLE,
+            ControlType::Color { .. } => protocol::OUTPUT_COLOR,
+            ControlType::Flash { .. } => protocol::OUTPUT_FLASH,
+            ControlType::TriggerEffect { .. } => protocol::OUTPUT_TRIGGERS,
+            ControlType::PlayerLeds(_) | ControlType::MuteLed(_) => protocol::OUTPUT_LEDS,
+            ControlType::Audio(_) => protocol::OUTPUT_AUDIO,
+            ControlType::Volume { .. } => protocol::OUTPUT_VOLUME,
+            ControlType::ConnectColor { .. }
+            | ControlType::Battery(_)
+            | ControlType::Settings(_) => 0,
+        }
+    }
+}
+
+// Gamepads ordered by sysname, position in this list is index for selector
+fn sorted_gamepads(gamepads: &HashMap<String, DSGamepad>) -> Vec<(&String, &DSGamepad)> {
+    let mut sorted: Vec<(&String, &DSGamepad)> = gamepads.iter().collect();
+    sorted.sort_by_key(|(sysname, _)| *sysname);
+    sorted
 }
 
-fn find_and_open_gamepad(gamepads: &Gamepads, src: SocketAddr) -> Option<(DSType, File, File)> {
+fn select_gamepad(
+    gamepads: &HashMap<String, DSGamepad>,
+    selector: &Selector,
+    config: &Config,
+) -> Option<String> {
+    let sorted = sorted_gamepads(gamepads);
+    let is_free = |v: &DSGamepad| v.used_by.is_none() && config.is_allowed(v.ds_type);
+    let (sysname, gamepad) = match selector {
+        Selector::Any => sorted.into_iter().find(|(_, v)| is_free(v))?,
+        Selector::Sysname(name) => sorted.into_iter().find(|(k, _)| *k == name)?,
+        Selector::Type(ds_type) => sorted
+            .into_iter()
+            .find(|(_, v)| is_free(v) && v.ds_type == *ds_type)?,
+        Selector::Index(index) => *sorted.get(*index as usize)?,
+        Selector::Mac(mac) => sorted
+            .into_iter()
+            .find(|(_, v)| v.mac.as_ref() == Some(mac))?,
+    };
+    if gamepad.used_by.is_some() {
+        info!(controller = sysname.as_str(); "Gamepad is already used by {:?}", gamepad.used_by);
+        return None;
+    }
+    if !config.is_allowed(gamepad.ds_type) {
+        info!(
+            controller = sysname.as_str();
+            "Gamepad of type {:?} is not allowed by configuration", gamepad.ds_type
+        );
+        return None;
+    }
+    Some(sysname.clone())
+}
+
+fn find_and_open_gamepad(
+    gamepads: &Gamepads,
+    src: SocketAddr,
+    selector: &Selector,
+    config: &Config,
+) -> Option<(String, DSType, DeviceReader, DeviceWriter, Arc<AtomicU64>)> {
     let mut locked_gamepads = gamepads.write();
+    let sysname = select_gamepad(&locked_gamepads, selector, config)?;
     // gamepad is not mut, because it stores &mut, so it works via interior mutability
-    let gamepad = locked_gamepads.values_mut().find(|v| v.used_by.is_none())?;
-    gamepad.used_by = Some(src);
+    let gamepad = locked_gamepads.get_mut(&sysname)?;
+    if let Some(idle) = gamepad.idle.take() {
+        idle.stop();
+    }
     let path = &gamepad.path;
-    let f_write = match OpenOptions::new().read(true).write(true).open(path) {
-        Ok(f_write) => f_write,
-        Err(e) => {
-            eprintln!("Error on opening {}: {}", path, e);
-            return None;
-        }
+    let opened = match &gamepad.backend {
+        Backend::Hidraw => hidraw::open_gamepad(path),
+        Backend::Virtual {
+            input,
+            output,
+            interval,
+        } => Ok(virtual_gamepad::open_gamepad(
+            input,
+            output,
+            gamepad.ds_type,
+            *interval,
+        )),
+        Backend::Replay { replay, session } => Ok(replay::open_gamepad(replay, session)),
     };
-    let f_read = match f_write.try_clone() {
-        Ok(f_read) => f_read,
+    let (mut f_read, mut f_write) = match opened {
+        Ok(streams) => streams,
         Err(e) => {
-            eprintln!("Error on cloning fd for {}: {}", path, e);
+            error!(client:% = src; "Error on opening {}: {}", path, e);
             return None;
         }
     };
-    Some((gamepad.ds_type, f_read, f_write))
+    gamepad.used_by = Some(src);
+    let ds_type = gamepad.ds_type;
+    let corrupt_frames = Arc::clone(&gamepad.corrupt_frames);
+    let record = config.recording(&sysname, gamepad.mac.as_deref()).cloned();
+    // capture file is opened without lock, it should not hold up other clients
+    drop(locked_gamepads);
+    if let Some(record) = record {
+        (f_read, f_write) = capture::record(f_read, f_write, &record, &sysname, ds_type);
+    }
+    Some((sysname, ds_type, f_read, f_write, corrupt_frames))
 }
 
+// Gamepad used by client, it is shared by input and control threads and released,
+// when both are done: after the last report is written and no more reports are read,
+// so next client could not mix its reports with the old ones
+struct Claim {
+    gamepads: Gamepads,
+    sysname: String,
+    addr: SocketAddr,
+    // thread, which keeps output going until next client, it is left to gamepad
+    idle: Mutex<Option<IdleOutput>>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(gamepad) = self.gamepads.write().get_mut(&self.sysname) {
+            if gamepad.used_by == Some(self.addr) {
+                gamepad.used_by = None;
+                gamepad.idle = self.idle.get_mut().take();
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn send_to_client<T: Packet + Default>(
     addr: SocketAddr,
+    slot: Option<u8>,
     client: UdpSocket,
-    mut f_read: File,
+    mut f_read: DeviceReader,
     global_stop: Arc<AtomicBool>,
     client_stop: Arc<AtomicBool>,
     sender: Sender<ControlType>,
+    corrupt_frames: Arc<AtomicU64>,
+    claim: Arc<Claim>,
 ) {
-    let mut bat_level = 0;
+    let mut battery: BatteryStatus = Default::default();
+    let mut bad_reports: u64 = 0;
     //let mut f_read = File::open(&hidraw_path).unwrap();
     while !client_stop.load(Ordering::SeqCst) && !global_stop.load(Ordering::SeqCst) {
         let mut packet: T = Default::default();
-        let mut new_packet: DS4PacketInner = [0; PACKET_LEN_USB];
-        match packet.read(&mut f_read) {
+        let new_packet: DS4PacketInner = match hidraw::read_packet(&mut f_read, &mut packet) {
             Ok(()) => {
-                let capacity = packet.battery_capacity();
-                if capacity != bat_level {
-                    eprintln!("Battery level changed for {} to {}%", addr, capacity);
-                    if let Err(e) = sender.send(ControlType::Battery(capacity)) {
-                        eprintln!("Error sending battery state to control thread: {}", e);
+                let new_battery = packet.decode().battery;
+                if new_battery != battery {
+                    info!(
+                        client:% = addr;
+                        "Battery status changed to {}% {:?}{}",
+                        new_battery.level,
+                        new_battery.state,
+                        if new_battery.cable { ", on cable" } else { "" }
+                    );
+                    if let Err(e) = sender.send(ControlType::Battery(new_battery)) {
+                        error!(client:% = addr; "Error sending battery state to control thread: {}", e);
                     }
-                    bat_level = capacity;
+                    battery = new_battery;
                 }
-                new_packet = packet.to_ds4_packet();
+                packet.to_ds4_packet()
             }
-            Err(_err) => {
-                //eprintln!("Error while reading from gamepad src={} err={}", addr, err);
-                new_packet[0] = 0x77;
+            // virtual gamepad has no input for now
+            Err(PacketError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(PacketError::Io(_err)) => {
+                //error!("Error while reading from gamepad src={} err={}", addr, err);
                 break;
             }
-        }
-        if let Err(err) = client.send_to(&new_packet, addr) {
-            eprintln!("Error on address src={} err={}", addr, err);
+            Err(PacketError::BadCrc) => {
+                let total = corrupt_frames.fetch_add(1, Ordering::Relaxed) + 1;
+                if total.is_power_of_two() {
+                    warn!(
+                        client:% = addr;
+                        "Dropping corrupt frame from gamepad, {} corrupt frames so far",
+                        total
+                    );
+                }
+                continue;
+            }
+            Err(err) => {
+                bad_reports += 1;
+                // do not flood the log, when gamepad sends only bad reports
+                if bad_reports.is_power_of_two() {
+                    warn!(
+                        client:% = addr;
+                        "Skipping bad report from gamepad: {}, {} bad reports so far",
+                        err, bad_reports
+                    );
+                }
+                continue;
+            }
+        };
+        let sent = match slot {
+            Some(slot) => {
+                let mut tagged = [0u8; PACKET_LEN_USB + 1];
+                tagged[0] = slot;
+                tagged[1..].copy_from_slice(&new_packet);
+                client.send_to(&tagged, addr)
+            }
+            None => client.send_to(&new_packet, addr),
+        };
+        if let Err(err) = sent {
+            error!(client:% = addr; "Error on sending report: {}", err);
             break;
         };
     }
     client_stop.store(true, Ordering::SeqCst);
-    eprintln!("Input thread stopped for {}", addr);
+    info!(
+        client:% = addr;
+        "Input thread stopped, dropped {} bad reports, {} corrupt frames from gamepad so far",
+        bad_reports,
+        corrupt_frames.load(Ordering::Relaxed)
+    );
+    drop(claim);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_input_thread(
     src: SocketAddr,
+    slot: Option<u8>,
     ds_type: DSType,
     global_stop: &Arc<AtomicBool>,
     client_stop: &Arc<AtomicBool>,
     socket: &UdpSocket,
-    f_read: File,
+    f_read: DeviceReader,
     s: &Sender<ControlType>,
-) -> Option<()> {
-    let send_thread_name = format!("send_to_client_{}", src);
+    corrupt_frames: Arc<AtomicU64>,
+    claim: Arc<Claim>,
+    config: &Config,
+) -> Option<JoinHandle<()>> {
+    let send_thread_name = format!("send_to_client_{}_{}", src, slot.unwrap_or_default());
     let global_stop = Arc::clone(global_stop);
     let client_stop = Arc::clone(client_stop);
     let sender = s.clone();
     let sock_w = match socket.try_clone() {
         Ok(sock_w) => sock_w,
         Err(e) => {
-            eprintln!("Error on cloning socket for client {}: {}", src, e);
+            error!(client:% = src; "Error on cloning socket: {}", e);
             return None;
         }
     };
@@ -130,48 +386,189 @@ fn create_input_thread(
         DSType::SenseBT => send_to_client::<DSensePacketBT>,
         DSType::SenseUSB => send_to_client::<DSensePacketUSB>,
     };
-    if let Err(err) = thread::Builder::new()
+    let input = match thread::Builder::new()
         .name(send_thread_name)
-        .spawn(move || f(src, sock_w, f_read, global_stop, client_stop, sender))
-    {
-        eprintln!("Error creating input thread for client {}: {}", src, err);
-        return None;
+        .spawn(move || {
+            f(
+                src,
+                slot,
+                sock_w,
+                f_read,
+                global_stop,
+                client_stop,
+                sender,
+                corrupt_frames,
+                claim,
+            )
+        }) {
+        Ok(input) => input,
+        Err(err) => {
+            error!(client:% = src; "Error creating input thread: {}", err);
+            return None;
+        }
+    };
+    let connect_flash = config.connect_flash();
+    if !connect_flash.is_zero() {
+        // ignoring result, as we don't care, now
+        let [r, g, b] = config.lightbar.connect_color;
+        let until = Instant::now() + connect_flash;
+        s.send(ControlType::ConnectColor { r, g, b, until }).ok();
+    }
+    Some(input)
+}
+
+fn write_packet<T: Controls>(dsc: &mut T, f_write: &mut DeviceWriter, is_bt: bool) {
+    if is_bt {
+        if let Err(e) = hidraw::write_report(f_write, &dsc.report_bt()) {
+            error!("Error on writing BT packet: {}", e);
+        }
+    } else if let Err(e) = hidraw::write_report(f_write, &dsc.report_usb()) {
+        error!("Error on writing USB packet: {}", e);
+    }
+}
+
+// Returns true, if output report should be written
+fn apply_control<T: Controls>(
+    dsc: &mut T,
+    audio: &mut AudioStream,
+    settings: &mut OutputSettings,
+    connect_until: &mut Option<Instant>,
+    control: ControlType,
+) -> bool {
+    match control {
+        ControlType::Rumble { large, small } => {
+            dsc.set_rumble(settings.scale_rumble(large), settings.scale_rumble(small));
+        }
+        // color of client is kept, even if it comes before connect flash is over
+        ControlType::Color { r, g, b } => {
+            dsc.set_color(r, g, b);
+            *connect_until = None;
+        }
+        ControlType::ConnectColor { r, g, b, until } => {
+            dsc.set_color(r, g, b);
+            *connect_until = Some(until);
+        }
+        ControlType::Flash { bright, dark } => {
+            dsc.set_flash(bright, dark);
+        }
+        ControlType::TriggerEffect { trigger, effect } => {
+            if !dsc.set_trigger_effect(trigger, effect) {
+                warn!("Gamepad does not support trigger effects");
+            }
+        }
+        ControlType::PlayerLeds(leds) => {
+            if !dsc.set_player_leds(leds) {
+                warn!("Gamepad does not support player LEDs");
+            }
+        }
+        ControlType::MuteLed(mute) => {
+            if !dsc.set_mute_led(mute) {
+                warn!("Gamepad does not support mute LED");
+            }
+        }
+        // audio is written by its own reports
+        ControlType::Audio(data) => {
+            if !audio.push(&data) {
+                warn!("Audio buffer is full, dropping {} bytes", data.len());
+            }
+            return false;
+        }
+        ControlType::Volume {
+            left,
+            right,
+            speaker,
+        } => {
+            if !dsc.set_volume(left, right, speaker) {
+                warn!("Gamepad does not support volume");
+            }
+        }
+        ControlType::Battery(status) => {
+            dsc.set_battery(status);
+        }
+        // new idle color is used, when client is gone, and new rumble scale with next rumble
+        ControlType::Settings(new_settings) => {
+            *settings = new_settings;
+            dsc.set_bt_latency(settings.bt_latency);
+        }
     }
-    // ignoring result, as we don't care, now
-    s.send(ControlType::Color { r: 0, g: 255, b: 0 }).ok();
-    thread::sleep(Duration::from_secs(1));
-    s.send(ControlType::Color { r: 0, g: 0, b: 255 }).ok();
-    Some(())
+    true
 }
 
-fn control_dsc<T: Controls + Default>(
-    mut f_write: File,
+// Keeps animations of gamepad, which is not used, going, until stop is dropped.
+// Lightbar is left without flash, if server stops.
+fn idle_output<T: Controls>(
+    mut dsc: T,
+    mut f_write: DeviceWriter,
+    is_bt: bool,
+    stop: Receiver<()>,
+    global_stop: Arc<AtomicBool>,
+) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(TICK_INTERVAL) {
+        if global_stop.load(Ordering::SeqCst) {
+            break;
+        }
+        if dsc.tick() {
+            write_packet(&mut dsc, &mut f_write, is_bt);
+        }
+    }
+    if global_stop.load(Ordering::SeqCst) {
+        let (bright, dark) = FLASH_NONE;
+        dsc.set_flash(bright, dark);
+        write_packet(&mut dsc, &mut f_write, is_bt);
+    }
+    debug!("Idle output thread stopped");
+}
+
+fn control_dsc<T: Controls + Default + Send + 'static>(
+    mut f_write: DeviceWriter,
     r: Receiver<ControlType>,
     global_stop: Arc<AtomicBool>,
     client_stop: Arc<AtomicBool>,
     is_bt: bool,
+    mut settings: OutputSettings,
+    claim: Arc<Claim>,
 ) {
     let mut dsc: T = Default::default();
+    let (red, green, blue) = settings.idle_color;
+    dsc.set_color(red, green, blue);
+    dsc.set_bt_latency(settings.bt_latency);
+    let mut audio = AudioStream::default();
+    let mut next_audio = Instant::now();
+    let mut last_tick = Instant::now();
+    let mut last_write = Instant::now();
+    let mut connect_until: Option<Instant> = None;
+    // there are changes, which are not written to gamepad yet
+    let mut dirty = false;
     while !global_stop.load(Ordering::SeqCst) && !client_stop.load(Ordering::SeqCst) {
-        match r.recv_timeout(Duration::from_millis(100)) {
-            Ok(result) => {
-                match result {
-                    ControlType::Rumble { large, small } => {
-                        dsc.set_rumble(large, small);
-                    }
-                    ControlType::Color { r, g, b } => {
-                        dsc.set_color(r, g, b);
-                    }
-                    ControlType::Battery(level) => {
-                        dsc.set_battery(level);
-                    }
-                }
-                if is_bt {
-                    if let Err(e) = dsc.write_packet_bt(&mut f_write) {
-                        eprintln!("Error on writing BT packet: {}", e);
-                    }
-                } else if let Err(e) = dsc.write_packet_usb(&mut f_write) {
-                    eprintln!("Error on writing USB packet: {}", e);
+        // wake up in time for whatever should be done next
+        let mut deadline = (last_tick + TICK_INTERVAL).min(last_write + REFRESH_INTERVAL);
+        if dirty {
+            deadline = deadline.min(last_write + MIN_WRITE_INTERVAL);
+        }
+        if audio.is_ready() {
+            deadline = deadline.min(next_audio);
+        }
+        if let Some(until) = connect_until {
+            deadline = deadline.min(until);
+        }
+        match r.recv_deadline(deadline) {
+            Ok(control) => {
+                dirty |= apply_control(
+                    &mut dsc,
+                    &mut audio,
+                    &mut settings,
+                    &mut connect_until,
+                    control,
+                );
+                // coalesce controls, which are already queued, into one report
+                for control in r.try_iter().take(r.len()) {
+                    dirty |= apply_control(
+                        &mut dsc,
+                        &mut audio,
+                        &mut settings,
+                        &mut connect_until,
+                        control,
+                    );
                 }
             }
             Err(RecvTimeoutError::Timeout) => (),
@@ -180,19 +577,90 @@ fn control_dsc<T: Controls + Default>(
                 break;
             }
         }
+        let now = Instant::now();
+        if connect_until.is_some_and(|until| now >= until) {
+            connect_until = None;
+            let (r, g, b) = settings.idle_color;
+            dsc.set_color(r, g, b);
+            dirty = true;
+        }
+        if now >= last_tick + TICK_INTERVAL {
+            last_tick = now;
+            dirty |= dsc.tick();
+        }
+        // gamepad falls back to defaults, if it does not get reports for some time
+        let write_due = (dirty && now >= last_write + MIN_WRITE_INTERVAL)
+            || now >= last_write + REFRESH_INTERVAL;
+        let mut written = false;
+        if audio.is_ready() && now >= next_audio {
+            // output, which is due, goes within audio report, if gamepad could take it
+            let output = if write_due && is_bt {
+                dsc.audio_output()
+            } else {
+                None
+            };
+            if let Some((pkt, duration)) = audio.next_report(output.as_deref()) {
+                if let Err(e) = hidraw::write_report(&mut f_write, &pkt) {
+                    error!("Error writing audio to gamepad: {}", e);
+                }
+                written = output.is_some();
+                // do not try to catch up, if client was late
+                next_audio = next_audio.max(now) + duration;
+            }
+        }
+        if write_due {
+            if !written {
+                write_packet(&mut dsc, &mut f_write, is_bt);
+            }
+            last_write = now;
+            dirty = false;
+        }
+    }
+    // do not leave gamepad rumbling or colored by client after it is gone,
+    // and show that gamepad waits for a new client, unless server itself stops
+    dsc.set_rumble(0, 0);
+    let (r, g, b) = settings.idle_color;
+    dsc.set_color(r, g, b);
+    let (bright, dark) = if global_stop.load(Ordering::SeqCst) {
+        FLASH_NONE
+    } else {
+        FLASH_WAITING
+    };
+    dsc.set_flash(bright, dark);
+    dsc.set_trigger_effect(Trigger::Left, TriggerEffect::Off);
+    dsc.set_trigger_effect(Trigger::Right, TriggerEffect::Off);
+    dsc.set_player_leds(PlayerLeds::Battery);
+    dsc.set_mute_led(MuteLed::Off);
+    write_packet(&mut dsc, &mut f_write, is_bt);
+    debug!("Control thread stopped");
+    if global_stop.load(Ordering::SeqCst) || !dsc.is_flash_emulated() {
+        return;
+    }
+    // waiting flash is shown until next client, even though this one is gone
+    let (stop, stopped) = bounded(0);
+    let idle_global_stop = Arc::clone(&global_stop);
+    match thread::Builder::new()
+        .name(format!("idle_output_{}", claim.sysname))
+        .spawn(move || idle_output(dsc, f_write, is_bt, stopped, idle_global_stop))
+    {
+        Ok(thread) => *claim.idle.lock() = Some(IdleOutput { stop, thread }),
+        Err(err) => error!("Error creating idle output thread: {}", err),
     }
-    eprintln!("Control thread stopped");
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_control_thread(
     src: SocketAddr,
+    slot: u8,
     ds_type: DSType,
     global_stop: &Arc<AtomicBool>,
     client_stop: &Arc<AtomicBool>,
-    f_write: File,
+    f_write: DeviceWriter,
     r: Receiver<ControlType>,
-) -> Option<()> {
-    let control_thread_name = format!("handle_control_{}", src);
+    settings: OutputSettings,
+    claim: Arc<Claim>,
+) -> Option<JoinHandle<()>> {
+    let control_thread_name = format!("handle_control_{}_{}", src, slot);
     let global_stop = Arc::clone(global_stop);
     let client_stop_thread = Arc::clone(client_stop);
     let x: (ControlFunc, bool) = match ds_type {
@@ -203,87 +671,640 @@ fn create_control_thread(
     };
     // NOTE: until https://github.com/rust-lang/rfcs/issues/2870 is fixed and in stable
     let (f, is_bt) = x;
-    if let Err(err) = thread::Builder::new()
+    match thread::Builder::new()
         .name(control_thread_name)
-        .spawn(move || f(f_write, r, global_stop, client_stop_thread, is_bt))
-    {
-        eprintln!("Error creating control thread for client {}: {}", src, err);
-        client_stop.store(true, Ordering::SeqCst);
-        return None;
+        .spawn(move || {
+            f(
+                f_write,
+                r,
+                global_stop,
+                client_stop_thread,
+                is_bt,
+                settings,
+                claim,
+            )
+        }) {
+        Ok(control) => Some(control),
+        Err(err) => {
+            error!(client:% = src, slot = slot; "Error creating control thread: {}", err);
+            client_stop.store(true, Ordering::SeqCst);
+            None
+        }
     }
-    Some(())
 }
 
+#[allow(clippy::too_many_arguments)]
+fn open_slot(
+    src: SocketAddr,
+    slot: u8,
+    handshake: bool,
+    socket: &UdpSocket,
+    gamepads: &Gamepads,
+    global_stop: &Arc<AtomicBool>,
+    selector: &Selector,
+    config: &Config,
+) -> Option<(DSType, Slot)> {
+    let (sysname, ds_type, f_read, f_write, corrupt_frames) =
+        find_and_open_gamepad(gamepads, src, selector, config)?;
+    let client_stop = Arc::new(AtomicBool::new(false));
+    let (s, r) = unbounded();
+    let tag = if handshake { Some(slot) } else { None };
+    let settings = config.output_settings();
+    let claim = Arc::new(Claim {
+        gamepads: Arc::clone(gamepads),
+        sysname: sysname.clone(),
+        addr: src,
+        idle: Mutex::new(None),
+    });
+    // gamepad is released by threads, or right away, if they could not be started
+    let started = create_control_thread(
+        src,
+        slot,
+        ds_type,
+        global_stop,
+        &client_stop,
+        f_write,
+        r,
+        settings,
+        Arc::clone(&claim),
+    )
+    .and_then(|control| {
+        create_input_thread(
+            src,
+            tag,
+            ds_type,
+            global_stop,
+            &client_stop,
+            socket,
+            f_read,
+            &s,
+            corrupt_frames,
+            claim,
+            config,
+        )
+        .map(|input| (control, input))
+    });
+    let (control, input) = match started {
+        Some(threads) => threads,
+        None => {
+            client_stop.store(true, Ordering::SeqCst);
+            return None;
+        }
+    };
+    // show player number of slot, like PlayStation does
+    if protocol::supported_outputs(ds_type) & protocol::OUTPUT_LEDS != 0 {
+        let _ = s.send(ControlType::PlayerLeds(PlayerLeds::Player(slot + 1)));
+    }
+    let slot = Slot {
+        sender: s,
+        stop: client_stop,
+        sysname,
+        ds_type,
+        control,
+        input,
+    };
+    Some((ds_type, slot))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_new_client(
     src: SocketAddr,
     socket: &UdpSocket,
     clients: &mut Clients,
     gamepads: &Gamepads,
     global_stop: &Arc<AtomicBool>,
+    selector: &Selector,
+    handshake: bool,
+    config: &Config,
+) -> Option<DSType> {
+    if let Some(client) = clients.remove(&src) {
+        info!(client:% = src; "Client connected again, closing old session");
+        // wait for old session to release its gamepad, as client could select it again
+        for slot in client.slots.into_iter().flatten() {
+            close_slot(slot).into_iter().for_each(join_thread);
+        }
+    }
+    let (ds_type, slot) = open_slot(
+        src,
+        0,
+        handshake,
+        socket,
+        gamepads,
+        global_stop,
+        selector,
+        config,
+    )?;
+    clients.insert(
+        src,
+        Client {
+            slots: vec![Some(slot)],
+            last_seen: Instant::now(),
+            handshake,
+            outputs: u8::MAX,
+            keepalives: false,
+        },
+    );
+    info!(client:% = src; "New client connected, {} clients now", clients.len());
+    debug!("Gamepads after connect {:?}", gamepads);
+    Some(ds_type)
+}
+
+fn send_reply(socket: &UdpSocket, src: SocketAddr, reply: &[u8]) {
+    if let Err(e) = socket.send_to(reply, src) {
+        error!(client:% = src; "Error on sending reply: {}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_hello(
+    src: SocketAddr,
+    socket: &UdpSocket,
+    clients: &mut Clients,
+    gamepads: &Gamepads,
+    global_stop: &Arc<AtomicBool>,
+    version: u8,
+    outputs: u8,
+    name: &str,
+    selector: &Selector,
+    config: &Config,
 ) {
-    let (ds_type, f_read, f_write) = match find_and_open_gamepad(gamepads, src) {
+    info!(client:% = src; "Hello from {}, protocol version {}", name, version);
+    if version != PROTOCOL_VERSION {
+        let reply = protocol::encode_error(ErrorCode::UnsupportedVersion, protocol::OP_HELLO);
+        send_reply(socket, src, &reply);
+        return;
+    }
+    match handle_new_client(
+        src,
+        socket,
+        clients,
+        gamepads,
+        global_stop,
+        selector,
+        true,
+        config,
+    ) {
+        Some(ds_type) => {
+            if let Some(client) = clients.get_mut(&src) {
+                client.outputs = outputs;
+            }
+            send_reply(socket, src, &protocol::encode_welcome(0, ds_type, outputs));
+        }
+        None => {
+            let reply = protocol::encode_error(ErrorCode::NoGamepad, protocol::OP_HELLO);
+            send_reply(socket, src, &reply);
+        }
+    }
+}
+
+// First slot without controller, which could be used for attach
+fn free_slot(clients: &Clients, src: SocketAddr) -> Option<u8> {
+    let client = clients.get(&src)?;
+    let index = match client.slots.iter().position(|v| v.is_none()) {
+        Some(index) => index,
+        None if client.slots.len() < MAX_SLOTS => client.slots.len(),
+        None => return None,
+    };
+    Some(index as u8)
+}
+
+fn handle_attach(
+    src: SocketAddr,
+    socket: &UdpSocket,
+    clients: &mut Clients,
+    gamepads: &Gamepads,
+    global_stop: &Arc<AtomicBool>,
+    selector: &Selector,
+    config: &Config,
+) {
+    let (index, outputs) = match clients.get(&src) {
+        Some(client) if client.handshake => match free_slot(clients, src) {
+            Some(index) => (index, client.outputs),
+            None => {
+                let reply = protocol::encode_error(ErrorCode::NoFreeSlot, protocol::OP_ATTACH);
+                send_reply(socket, src, &reply);
+                return;
+            }
+        },
+        _ => {
+            let reply = protocol::encode_error(ErrorCode::NotConnected, protocol::OP_ATTACH);
+            send_reply(socket, src, &reply);
+            return;
+        }
+    };
+    let (ds_type, slot) = match open_slot(
+        src,
+        index,
+        true,
+        socket,
+        gamepads,
+        global_stop,
+        selector,
+        config,
+    ) {
         Some(x) => x,
+        None => {
+            let reply = protocol::encode_error(ErrorCode::NoGamepad, protocol::OP_ATTACH);
+            send_reply(socket, src, &reply);
+            return;
+        }
+    };
+    if let Some(client) = clients.get_mut(&src) {
+        let i = index as usize;
+        if i == client.slots.len() {
+            client.slots.push(None);
+        }
+        client.slots[i] = Some(slot);
+    }
+    info!(client:% = src, slot = index; "Client attached {:?}", ds_type);
+    send_reply(
+        socket,
+        src,
+        &protocol::encode_welcome(index, ds_type, outputs),
+    );
+}
+
+// Returns control and input threads, which could be joined to wait for neutral report,
+// gamepad is released, when both are stopped
+fn close_slot(slot: Slot) -> [JoinHandle<()>; 2] {
+    slot.stop.store(true, Ordering::SeqCst);
+    [slot.control, slot.input]
+}
+
+fn join_thread(thread: JoinHandle<()>) {
+    let name = thread.thread().name().unwrap_or_default().to_string();
+    if thread.join().is_err() {
+        error!("Thread {} panicked", name);
+    }
+}
+
+// Closes all sessions on shutdown and waits, until gamepads get neutral reports
+fn close_all_clients(clients: Clients) {
+    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+    for (_, client) in clients {
+        for slot in client.slots.into_iter().flatten() {
+            threads.extend(close_slot(slot));
+        }
+    }
+    threads.into_iter().for_each(join_thread);
+}
+
+// Turns off waiting flash of gamepads, which are not used, when server stops
+fn stop_idle_outputs(gamepads: &Gamepads) {
+    let idle: Vec<IdleOutput> = gamepads
+        .write()
+        .values_mut()
+        .filter_map(|gamepad| gamepad.idle.take())
+        .collect();
+    idle.into_iter().for_each(IdleOutput::stop);
+}
+
+fn handle_detach(src: SocketAddr, index: u8, clients: &mut Clients) {
+    let slot = clients
+        .get_mut(&src)
+        .and_then(|client| client.slots.get_mut(index as usize))
+        .and_then(|slot| slot.take());
+    if let Some(slot) = slot {
+        info!(client:% = src, slot = index; "Client detached slot");
+        close_slot(slot);
+    }
+}
+
+fn handle_control(
+    socket: &UdpSocket,
+    clients: &Clients,
+    src: SocketAddr,
+    slot: u8,
+    opcode: u8,
+    control: ControlType,
+) {
+    let client = match clients.get(&src) {
+        Some(client) => client,
         None => return,
     };
-    let client_stop = Arc::new(AtomicBool::new(false));
-    let (s, r) = unbounded();
-    if create_control_thread(src, ds_type, global_stop, &client_stop, f_write, r).is_none() {
-        return;
+    // legacy clients have only one controller, and could send anything instead of slot
+    let index = if client.handshake { slot as usize } else { 0 };
+    if let Some(Some(slot)) = client.slots.get(index) {
+        // controller could not do it, or client did not ask for it in hello
+        let granted = protocol::supported_outputs(slot.ds_type) & client.outputs;
+        if granted & control.output() != control.output() {
+            let reply = protocol::encode_error(ErrorCode::Unsupported, opcode);
+            send_reply(socket, src, &reply);
+            return;
+        }
+        if let Err(e) = slot.sender.send(control) {
+            error!(client:% = src; "Error sending control to control thread: {}", e);
+        }
     };
-    if create_input_thread(src, ds_type, global_stop, &client_stop, socket, f_read, &s).is_none() {
-        return;
+}
+
+fn handle_disconnect(addr: SocketAddr, clients: &mut Clients) {
+    if let Some(client) = clients.remove(&addr) {
+        for slot in client.slots.into_iter().flatten() {
+            close_slot(slot);
+        }
     }
-    clients.insert(src, s);
-    eprintln!("New client connected {:?}", clients);
-    eprintln!("Gamepads after connect {:?}", gamepads);
+    info!(client:% = addr; "Client disconnected");
 }
 
-fn handle_rumble(clients: &Clients, src: SocketAddr, large: u8, small: u8) {
-    if let Some(sender) = clients.get(&src) {
-        if let Err(e) = sender.send(ControlType::Rumble { large, small }) {
-            eprintln!("Error sending rumble to control thread: {}", e);
+// Closes sessions, which did not send keepalive in time (legacy clients, which never sent it,
+// get longer timeout for any request), and slots, which threads are stopped.
+// Sessions without slots are closed too.
+fn reap_clients(clients: &mut Clients) {
+    let mut expired: Vec<SocketAddr> = Vec::new();
+    for (addr, client) in clients.iter_mut() {
+        let timeout = if client.handshake || client.keepalives {
+            KEEPALIVE_TIMEOUT
+        } else {
+            LEGACY_TIMEOUT
+        };
+        if client.last_seen.elapsed() > timeout {
+            warn!(client:% = addr; "Client did not send keepalive in time");
+            expired.push(*addr);
+            continue;
+        }
+        for (index, slot) in client.slots.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|v| v.stop.load(Ordering::SeqCst)) {
+                info!(client:% = addr, slot = index; "Slot stopped");
+                if let Some(slot) = slot.take() {
+                    close_slot(slot);
+                }
+            }
+        }
+        if client.slots.iter().all(|v| v.is_none()) {
+            expired.push(*addr);
         }
+    }
+    for addr in expired {
+        info!(client:% = addr; "Session expired");
+        handle_disconnect(addr, clients);
+    }
+}
+
+// Prefer gamepad, which was used by the same client in the same slot before
+// (maybe, over other connection), if client does not care which one to use
+fn remembered_selector(
+    selector: Selector,
+    src: SocketAddr,
+    slot: u8,
+    known_macs: &KnownMacs,
+    gamepads: &Gamepads,
+) -> Selector {
+    let mac = match (&selector, known_macs.get(&(src.ip(), slot))) {
+        (Selector::Any, Some(mac)) => mac,
+        _ => return selector,
     };
+    let is_free = gamepads
+        .read()
+        .values()
+        .any(|v| v.mac.as_ref() == Some(mac) && v.used_by.is_none());
+    if is_free {
+        Selector::Mac(mac.clone())
+    } else {
+        selector
+    }
 }
 
-fn handle_disconnect(addr: SocketAddr, clients: &mut Clients, gamepads: &Gamepads) {
-    clients.remove(&addr);
-    if let Some(gamepad) = gamepads
-        .write()
-        .values_mut()
-        .find(|v| v.used_by == Some(addr))
-    {
-        gamepad.used_by = None;
+fn remember_gamepads(
+    src: SocketAddr,
+    clients: &Clients,
+    known_macs: &mut KnownMacs,
+    gamepads: &Gamepads,
+) {
+    let client = match clients.get(&src) {
+        Some(client) => client,
+        None => return,
+    };
+    let locked_gamepads = gamepads.read();
+    for (index, slot) in client.slots.iter().enumerate() {
+        let mac = slot
+            .as_ref()
+            .and_then(|slot| locked_gamepads.get(&slot.sysname))
+            .and_then(|v| v.mac.clone());
+        if let Some(mac) = mac {
+            known_macs.insert((src.ip(), index as u8), mac);
+        }
     }
-    eprintln!("Client {} disconnected {:?}", addr, gamepads.read());
+}
+
+// Applies new configuration to running sessions, listen address could not be changed
+fn reload_config(args: &Args, config: &mut Config, clients: &mut Clients) {
+    let mut new_config = match Config::from_args(args) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("Keeping old configuration: {}", e);
+            return;
+        }
+    };
+    for name in new_config.keep_restart_settings(config) {
+        warn!("Change of {} needs restart of server", name);
+    }
+    // access lists apply to running sessions too
+    let denied: Vec<SocketAddr> = clients
+        .keys()
+        .filter(|addr| !new_config.is_client_allowed(addr.ip()))
+        .copied()
+        .collect();
+    for addr in denied {
+        warn!(client:% = addr; "Client is not allowed anymore");
+        handle_disconnect(addr, clients);
+    }
+    for (addr, client) in clients.iter_mut() {
+        for (index, slot) in client.slots.iter_mut().enumerate() {
+            if slot
+                .as_ref()
+                .is_some_and(|v| !new_config.is_allowed(v.ds_type))
+            {
+                warn!(client:% = addr, slot = index; "Controller type is not allowed anymore");
+                if let Some(slot) = slot.take() {
+                    close_slot(slot);
+                }
+            }
+        }
+    }
+    let settings = new_config.output_settings();
+    for slot in clients.values().flat_map(|v| v.slots.iter().flatten()) {
+        slot.sender.send(ControlType::Settings(settings)).ok();
+    }
+    logging::apply(new_config.log_filters(), new_config.log_output());
+    *config = new_config;
+    info!("Configuration reloaded");
 }
 
 fn handle_udp(
     mut clients: Clients,
     global_stop: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
     gamepads: Gamepads,
+    args: Args,
+    mut config: Config,
 ) -> io::Result<()> {
-    let mut buf = [0u8; 4];
-    let socket = UdpSocket::bind("[::]:9999")?;
+    // large enough for audio datagrams
+    let mut buf = [0u8; 1500];
+    let mut known_macs: KnownMacs = HashMap::new();
+    let socket = UdpSocket::bind(config.listen_addr())?;
+    // port could be 0, then it is chosen by system
+    info!("Listening on {}", socket.local_addr()?);
     //let mut writer = unsafe { File::from_raw_fd(1) };
     socket.set_read_timeout(Some(Duration::from_secs(1)))?;
     while !global_stop.load(Ordering::SeqCst) {
-        let (amt, src) = match socket.recv_from(&mut buf) {
+        if reload.swap(false, Ordering::SeqCst) {
+            reload_config(&args, &mut config, &mut clients);
+        }
+        let received = socket.recv_from(&mut buf);
+        reap_clients(&mut clients);
+        let (amt, src) = match received {
             Ok((amt, src)) => (amt, src),
             Err(_e) => continue,
         };
-        let buf = &mut buf[..amt];
-        match buf[0] {
-            0 => handle_new_client(src, &socket, &mut clients, &gamepads, &global_stop),
-            1 => handle_rumble(&clients, src, buf[1], buf[2]),
-            2 => handle_disconnect(src, &mut clients, &gamepads),
-            _ => panic!("Bohuzel"),
+        if let Some(client) = clients.get_mut(&src) {
+            client.last_seen = Instant::now();
+        }
+        let buf = &buf[..amt];
+        if !config.is_client_allowed(src.ip()) {
+            warn!(client:% = src; "Request from client, which is not allowed");
+            let opcode = buf.first().copied().unwrap_or_default();
+            send_reply(
+                &socket,
+                src,
+                &protocol::encode_error(ErrorCode::NotAllowed, opcode),
+            );
+            continue;
+        }
+        match protocol::parse_request(buf) {
+            Ok(Request::Connect { selector }) => {
+                let selector = remembered_selector(selector, src, 0, &known_macs, &gamepads);
+                handle_new_client(
+                    src,
+                    &socket,
+                    &mut clients,
+                    &gamepads,
+                    &global_stop,
+                    &selector,
+                    false,
+                    &config,
+                );
+                remember_gamepads(src, &clients, &mut known_macs, &gamepads);
+            }
+            Ok(Request::Rumble { large, small, slot }) => {
+                let control = ControlType::Rumble { large, small };
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::Color { r, g, b, slot }) => {
+                let control = ControlType::Color { r, g, b };
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::Flash { bright, dark, slot }) => {
+                let control = ControlType::Flash { bright, dark };
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::TriggerEffect {
+                trigger,
+                effect,
+                slot,
+            }) => {
+                let control = ControlType::TriggerEffect { trigger, effect };
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::PlayerLeds { leds, slot }) => {
+                let control = ControlType::PlayerLeds(leds);
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::MuteLed { mute, slot }) => {
+                let control = ControlType::MuteLed(mute);
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::Audio { data, slot }) => {
+                let control = ControlType::Audio(data);
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::Volume {
+                left,
+                right,
+                speaker,
+                slot,
+            }) => {
+                let control = ControlType::Volume {
+                    left,
+                    right,
+                    speaker,
+                };
+                handle_control(&socket, &clients, src, slot, buf[0], control);
+            }
+            Ok(Request::Disconnect) => handle_disconnect(src, &mut clients),
+            Ok(Request::Hello {
+                version,
+                outputs,
+                name,
+                selector,
+            }) => {
+                let selector = remembered_selector(selector, src, 0, &known_macs, &gamepads);
+                handle_hello(
+                    src,
+                    &socket,
+                    &mut clients,
+                    &gamepads,
+                    &global_stop,
+                    version,
+                    outputs,
+                    &name,
+                    &selector,
+                    &config,
+                );
+                remember_gamepads(src, &clients, &mut known_macs, &gamepads);
+            }
+            Ok(Request::KeepAlive) => {
+                if let Some(client) = clients.get_mut(&src) {
+                    client.keepalives = true;
+                }
+                send_reply(&socket, src, &protocol::encode_keepalive_ack());
+            }
+            Ok(Request::List) => {
+                let reply = protocol::encode_list(&sorted_gamepads(&gamepads.read()));
+                send_reply(&socket, src, &reply);
+            }
+            Ok(Request::Attach { selector }) => {
+                let slot = free_slot(&clients, src).unwrap_or_default();
+                let selector = remembered_selector(selector, src, slot, &known_macs, &gamepads);
+                handle_attach(
+                    src,
+                    &socket,
+                    &mut clients,
+                    &gamepads,
+                    &global_stop,
+                    &selector,
+                    &config,
+                );
+                remember_gamepads(src, &clients, &mut known_macs, &gamepads);
+            }
+            Ok(Request::Detach { slot }) => handle_detach(src, slot, &mut clients),
+            Err(code) => {
+                warn!(client:% = src; "Bad request: {:?} {:?}", code, buf);
+                let opcode = buf.first().copied().unwrap_or_default();
+                send_reply(&socket, src, &protocol::encode_error(code, opcode));
+            }
         };
     }
+    close_all_clients(clients);
+    stop_idle_outputs(&gamepads);
     Ok(())
 }
 
 fn main() -> io::Result<()> {
+    let parsed = config::parse_args(std::env::args().skip(1))
+        .and_then(|args| Config::from_args(&args).map(|config| (args, config)));
+    let (args, config) = match parsed {
+        Ok(parsed) => parsed,
+        Err(config::ConfigError::Help) => {
+            println!("{}", config::ConfigError::Help);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    logging::apply(config.log_filters(), config.log_output());
     let gamepads: Gamepads = Arc::new(RwLock::new(HashMap::new()));
     let clients: Clients = HashMap::new();
     let stop = Arc::new(AtomicBool::new(false));
@@ -291,8 +1312,17 @@ fn main() -> io::Result<()> {
     signal_hook::flag::register_conditional_shutdown(SIGQUIT, 1, Arc::clone(&stop))?;
     signal_hook::flag::register(SIGTERM, Arc::clone(&stop))?;
     signal_hook::flag::register(SIGQUIT, Arc::clone(&stop))?;
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
 
-    udevmon::start_monitor(&gamepads, Arc::clone(&stop));
+    if let Err(e) = udevmon::start_monitor(&gamepads, Arc::clone(&stop)) {
+        error!(
+            "Could not monitor udev, only virtual gamepads are available: {}",
+            e
+        );
+    }
+    virtual_gamepad::register(&gamepads, &config.virtual_gamepads);
+    replay::register(&gamepads, &config.replays);
 
     //let mut f_read = unsafe { File::from_raw_fd(0) };
     //let mut f_read = File::open("/dev/hidraw0")?;
@@ -302,6 +1332,6 @@ fn main() -> io::Result<()> {
     // using stdout or stderr
     // Could use a io::Stdin here, but it's line buffered
     //let mut f_write = unsafe { File::from_raw_fd(1) };
-    handle_udp(clients, stop, gamepads)?;
+    handle_udp(clients, stop, reload, gamepads, args, config)?;
     Ok(())
 }
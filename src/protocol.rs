@@ -0,0 +1,612 @@
+// Wire format of UDP datagrams between client and server.
+//
+// Client sends requests, first byte is opcode:
+//   0x00 connect (legacy, no handshake): optional selector
+//   0x01 rumble: large, small, slot (ignored for legacy clients)
+//   0x02 disconnect, releases all controllers of client
+//   0x03 hello: version, requested outputs, name length, name (UTF-8),
+//        optional selector, controller is attached to slot 0, requests for outputs,
+//        which were not granted in welcome, are rejected with error
+//   0x04 keepalive, clients connected with hello must send it at least
+//        every KEEPALIVE_TIMEOUT, otherwise session is closed, so must legacy
+//        clients after their first keepalive, legacy clients, which never sent it,
+//        are closed after LEGACY_TIMEOUT without any request
+//   0x05 list controllers
+//   0x06 attach one more controller to next free slot: optional selector
+//   0x07 detach controller: slot
+//   0x08 lightbar color: red, green, blue, slot (ignored for legacy clients),
+//        server default color is restored, when client disconnects
+//   0x09 lightbar flash: time to stay bright, time to stay dark (both in 10ms units,
+//        0 disables flashing), slot (ignored for legacy clients)
+//   0x0A adaptive trigger effect (DualSense only): trigger (0 is L2, 1 is R2),
+//        mode, three parameters, slot (ignored for legacy clients), modes are
+//        0 off, 1 resistance (start zone 0-9, strength 1-8),
+//        2 weapon (start zone 2-7, end zone up to 8, strength 1-8),
+//        3 vibration (start zone 0-9, amplitude 1-8, frequency in Hz)
+//   0x0B player LEDs (DualSense only): player number 1-4 or 0 for battery gauge,
+//        slot (ignored for legacy clients), by default slot N shows player N+1
+//   0x0C mute LED (DualSense only): 0 off, 1 on, 2 pulse, slot (ignored for legacy clients)
+//   0x0D audio (DS4 over BT only): slot (ignored for legacy clients), SBC frames,
+//        which are played at their own pace, frames are never split between reports
+//   0x0E volume (DS4 only): headphones left, headphones right, speaker, slot
+//        (ignored for legacy clients)
+//
+// Selector chooses controller to connect to, first byte is its type:
+//   0x00 first free controller (same as no selector)
+//   0x01 hidraw sysname: length, sysname
+//   0x02 controller type, connection type
+//   0x03 index in controllers list
+//   0x04 MAC address: length, MAC as aa:bb:cc:dd:ee:ff
+//
+// Server sends input reports (64 bytes, in DS4 USB format), to clients connected
+// with hello they are prefixed with slot, so datagram is 65 bytes long.
+// Replies have opcodes 0x80 and above, so they could not be confused with reports:
+//   0x80 welcome: version, slot, controller type, connection type, granted outputs
+//   0x81 error: error code, opcode of request which caused it
+//   0x82 keepalive acknowledgement
+//   0x83 controllers list: count (at most 255), then for every controller ordered by sysname
+//        index, controller type, connection type, sysname length, sysname,
+//        owner length, owner address (empty, if controller is free),
+//        MAC length, MAC (empty, if unknown), firmware version (u32 LE, 0 if unknown),
+//        count of corrupt BT frames dropped since controller was found (u64 LE)
+//
+// Controller type is 0 for DS4 and 1 for DualSense,
+// connection type is 0 for USB and 1 for BT.
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::common_output::{MuteLed, PlayerLeds, Trigger, TriggerEffect};
+use crate::udevmon::{DSGamepad, DSType};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const LEGACY_TIMEOUT: Duration = Duration::from_secs(300);
+pub const MAX_SLOTS: usize = 4;
+
+// Bits of outputs, which client could request in hello
+pub const OUTPUT_RUMBLE: u8 = 0x01;
+pub const OUTPUT_COLOR: u8 = 0x02;
+pub const OUTPUT_FLASH: u8 = 0x04;
+pub const OUTPUT_TRIGGERS: u8 = 0x08;
+pub const OUTPUT_LEDS: u8 = 0x10;
+pub const OUTPUT_AUDIO: u8 = 0x20;
+pub const OUTPUT_VOLUME: u8 = 0x40;
+
+const OP_CONNECT: u8 = 0x00;
+const OP_RUMBLE: u8 = 0x01;
+const OP_DISCONNECT: u8 = 0x02;
+pub const OP_HELLO: u8 = 0x03;
+const OP_KEEPALIVE: u8 = 0x04;
+const OP_LIST: u8 = 0x05;
+pub const OP_ATTACH: u8 = 0x06;
+const OP_DETACH: u8 = 0x07;
+const OP_COLOR: u8 = 0x08;
+const OP_FLASH: u8 = 0x09;
+const OP_TRIGGER: u8 = 0x0A;
+const OP_PLAYER_LEDS: u8 = 0x0B;
+const OP_MUTE_LED: u8 = 0x0C;
+const OP_AUDIO: u8 = 0x0D;
+const OP_VOLUME: u8 = 0x0E;
+
+const OP_WELCOME: u8 = 0x80;
+const OP_ERROR: u8 = 0x81;
+const OP_KEEPALIVE_ACK: u8 = 0x82;
+const OP_LIST_REPLY: u8 = 0x83;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selector {
+    Any,
+    Sysname(String),
+    Type(DSType),
+    Index(u8),
+    Mac(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    Connect {
+        selector: Selector,
+    },
+    Rumble {
+        large: u8,
+        small: u8,
+        slot: u8,
+    },
+    Disconnect,
+    Hello {
+        version: u8,
+        outputs: u8,
+        name: String,
+        selector: Selector,
+    },
+    KeepAlive,
+    List,
+    Attach {
+        selector: Selector,
+    },
+    Detach {
+        slot: u8,
+    },
+    Color {
+        r: u8,
+        g: u8,
+        b: u8,
+        slot: u8,
+    },
+    Flash {
+        bright: u8,
+        dark: u8,
+        slot: u8,
+    },
+    TriggerEffect {
+        trigger: Trigger,
+        effect: TriggerEffect,
+        slot: u8,
+    },
+    PlayerLeds {
+        leds: PlayerLeds,
+        slot: u8,
+    },
+    MuteLed {
+        mute: MuteLed,
+        slot: u8,
+    },
+    Audio {
+        data: Vec<u8>,
+        slot: u8,
+    },
+    Volume {
+        left: u8,
+        right: u8,
+        speaker: u8,
+        slot: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownOpcode = 1,
+    Malformed = 2,
+    UnsupportedVersion = 3,
+    NoGamepad = 4,
+    NotConnected = 5,
+    NoFreeSlot = 6,
+    Unsupported = 7,
+    NotAllowed = 8,
+}
+
+// Returns string prefixed with its length, and rest of buffer
+fn parse_string(buf: &[u8]) -> Result<(String, &[u8]), ErrorCode> {
+    match buf {
+        [len, rest @ ..] if rest.len() >= *len as usize => {
+            let (s, rest) = rest.split_at(*len as usize);
+            Ok((String::from_utf8_lossy(s).into_owned(), rest))
+        }
+        _ => Err(ErrorCode::Malformed),
+    }
+}
+
+fn parse_selector(buf: &[u8]) -> Result<Selector, ErrorCode> {
+    match buf {
+        [] | [0x00, ..] => Ok(Selector::Any),
+        [0x01, rest @ ..] => Ok(Selector::Sysname(parse_string(rest)?.0)),
+        [0x02, controller, connection, ..] => ds_type_from(*controller, *connection)
+            .map(Selector::Type)
+            .ok_or(ErrorCode::Malformed),
+        [0x03, index, ..] => Ok(Selector::Index(*index)),
+        [0x04, rest @ ..] => Ok(Selector::Mac(parse_string(rest)?.0.to_lowercase())),
+        _ => Err(ErrorCode::Malformed),
+    }
+}
+
+fn parse_trigger_effect(mode: u8, p1: u8, p2: u8, p3: u8) -> Result<TriggerEffect, ErrorCode> {
+    let effect = match mode {
+        0 => TriggerEffect::Off,
+        1 => TriggerEffect::Resistance {
+            start: p1,
+            strength: p2,
+        },
+        2 => TriggerEffect::Weapon {
+            start: p1,
+            end: p2,
+            strength: p3,
+        },
+        3 => TriggerEffect::Vibration {
+            start: p1,
+            amplitude: p2,
+            frequency: p3,
+        },
+        _ => return Err(ErrorCode::Malformed),
+    };
+    if !effect.is_valid() {
+        return Err(ErrorCode::Malformed);
+    }
+    Ok(effect)
+}
+
+pub fn parse_request(buf: &[u8]) -> Result<Request, ErrorCode> {
+    let (opcode, payload) = buf.split_first().ok_or(ErrorCode::Malformed)?;
+    match *opcode {
+        OP_CONNECT => Ok(Request::Connect {
+            selector: parse_selector(payload)?,
+        }),
+        OP_RUMBLE => match payload {
+            [large, small, rest @ ..] => Ok(Request::Rumble {
+                large: *large,
+                small: *small,
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_DISCONNECT => Ok(Request::Disconnect),
+        OP_HELLO => match payload {
+            [version, outputs, rest @ ..] => {
+                let (name, rest) = parse_string(rest)?;
+                Ok(Request::Hello {
+                    version: *version,
+                    outputs: *outputs,
+                    name,
+                    selector: parse_selector(rest)?,
+                })
+            }
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_KEEPALIVE => Ok(Request::KeepAlive),
+        OP_LIST => Ok(Request::List),
+        OP_ATTACH => Ok(Request::Attach {
+            selector: parse_selector(payload)?,
+        }),
+        OP_DETACH => match payload {
+            [slot, ..] => Ok(Request::Detach { slot: *slot }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_COLOR => match payload {
+            [r, g, b, rest @ ..] => Ok(Request::Color {
+                r: *r,
+                g: *g,
+                b: *b,
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_FLASH => match payload {
+            [bright, dark, rest @ ..] => Ok(Request::Flash {
+                bright: *bright,
+                dark: *dark,
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_TRIGGER => match payload {
+            [trigger, mode, p1, p2, p3, rest @ ..] => Ok(Request::TriggerEffect {
+                trigger: match trigger {
+                    0 => Trigger::Left,
+                    1 => Trigger::Right,
+                    _ => return Err(ErrorCode::Malformed),
+                },
+                effect: parse_trigger_effect(*mode, *p1, *p2, *p3)?,
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_PLAYER_LEDS => match payload {
+            [number, rest @ ..] => {
+                let leds = match number {
+                    0 => PlayerLeds::Battery,
+                    _ => PlayerLeds::Player(*number),
+                };
+                if !leds.is_valid() {
+                    return Err(ErrorCode::Malformed);
+                }
+                Ok(Request::PlayerLeds {
+                    leds,
+                    slot: rest.first().copied().unwrap_or_default(),
+                })
+            }
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_MUTE_LED => match payload {
+            [mute, rest @ ..] => Ok(Request::MuteLed {
+                mute: match mute {
+                    0 => MuteLed::Off,
+                    1 => MuteLed::On,
+                    2 => MuteLed::Pulse,
+                    _ => return Err(ErrorCode::Malformed),
+                },
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_AUDIO => match payload {
+            [slot, data @ ..] if !data.is_empty() => Ok(Request::Audio {
+                data: data.to_vec(),
+                slot: *slot,
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        OP_VOLUME => match payload {
+            [left, right, speaker, rest @ ..] => Ok(Request::Volume {
+                left: *left,
+                right: *right,
+                speaker: *speaker,
+                slot: rest.first().copied().unwrap_or_default(),
+            }),
+            _ => Err(ErrorCode::Malformed),
+        },
+        _ => Err(ErrorCode::UnknownOpcode),
+    }
+}
+
+pub fn supported_outputs(ds_type: DSType) -> u8 {
+    match ds_type {
+        // DS4 over USB is an audio device by itself
+        DSType::DS4USB => OUTPUT_RUMBLE | OUTPUT_COLOR | OUTPUT_FLASH | OUTPUT_VOLUME,
+        DSType::DS4BT => OUTPUT_RUMBLE | OUTPUT_COLOR | OUTPUT_FLASH | OUTPUT_VOLUME | OUTPUT_AUDIO,
+        DSType::SenseBT | DSType::SenseUSB => {
+            OUTPUT_RUMBLE | OUTPUT_COLOR | OUTPUT_FLASH | OUTPUT_TRIGGERS | OUTPUT_LEDS
+        }
+    }
+}
+
+fn controller_type(ds_type: DSType) -> u8 {
+    match ds_type {
+        DSType::DS4BT | DSType::DS4USB => 0,
+        DSType::SenseBT | DSType::SenseUSB => 1,
+    }
+}
+
+fn connection_type(ds_type: DSType) -> u8 {
+    match ds_type {
+        DSType::DS4USB | DSType::SenseUSB => 0,
+        DSType::DS4BT | DSType::SenseBT => 1,
+    }
+}
+
+fn ds_type_from(controller: u8, connection: u8) -> Option<DSType> {
+    match (controller, connection) {
+        (0, 0) => Some(DSType::DS4USB),
+        (0, 1) => Some(DSType::DS4BT),
+        (1, 0) => Some(DSType::SenseUSB),
+        (1, 1) => Some(DSType::SenseBT),
+        _ => None,
+    }
+}
+
+pub fn encode_welcome(slot: u8, ds_type: DSType, outputs: u8) -> [u8; 6] {
+    [
+        OP_WELCOME,
+        PROTOCOL_VERSION,
+        slot,
+        controller_type(ds_type),
+        connection_type(ds_type),
+        outputs & supported_outputs(ds_type),
+    ]
+}
+
+pub fn encode_keepalive_ack() -> [u8; 1] {
+    [OP_KEEPALIVE_ACK]
+}
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len().min(u8::MAX as usize);
+    buf.push(len as u8);
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+// gamepads should be already ordered, their position is index for selector
+pub fn encode_list(gamepads: &[(&String, &DSGamepad)]) -> Vec<u8> {
+    // Count and index are single bytes, controllers past 255th are not listed
+    let count = u8::try_from(gamepads.len()).unwrap_or(u8::MAX);
+    let mut buf = vec![OP_LIST_REPLY, count];
+    for (index, (sysname, gamepad)) in (0..count).zip(gamepads) {
+        buf.push(index);
+        buf.push(controller_type(gamepad.ds_type));
+        buf.push(connection_type(gamepad.ds_type));
+        push_string(&mut buf, sysname);
+        let owner = gamepad.used_by.map(|addr| addr.to_string());
+        push_string(&mut buf, owner.as_deref().unwrap_or_default());
+        push_string(&mut buf, gamepad.mac.as_deref().unwrap_or_default());
+        buf.extend_from_slice(&gamepad.firmware.unwrap_or_default().to_le_bytes());
+        let corrupt_frames = gamepad.corrupt_frames.load(Ordering::Relaxed);
+        buf.extend_from_slice(&corrupt_frames.to_le_bytes());
+    }
+    buf
+}
+
+pub fn encode_error(code: ErrorCode, opcode: u8) -> [u8; 3] {
+    [OP_ERROR, code as u8, opcode]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hello() {
+        let buf = [OP_HELLO, 1, OUTPUT_RUMBLE, 4, b'g', b'a', b'm', b'e'];
+        assert_eq!(
+            parse_request(&buf),
+            Ok(Request::Hello {
+                version: 1,
+                outputs: OUTPUT_RUMBLE,
+                name: String::from("game"),
+                selector: Selector::Any,
+            })
+        );
+        assert_eq!(parse_request(&buf[..6]), Err(ErrorCode::Malformed));
+    }
+
+    #[test]
+    fn parse_selector() {
+        let buf = [
+            OP_HELLO, 1, 0, 0, 0x01, 7, b'h', b'i', b'd', b'r', b'a', b'w', b'3',
+        ];
+        match parse_request(&buf) {
+            Ok(Request::Hello { selector, .. }) => {
+                assert_eq!(selector, Selector::Sysname(String::from("hidraw3")))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            parse_request(&[OP_CONNECT, 0x02, 1, 1]),
+            Ok(Request::Connect {
+                selector: Selector::Type(DSType::SenseBT)
+            })
+        );
+        assert_eq!(
+            parse_request(&[OP_CONNECT, 0x02, 2, 1]),
+            Err(ErrorCode::Malformed)
+        );
+    }
+
+    #[test]
+    fn parse_legacy() {
+        assert_eq!(
+            parse_request(&[OP_CONNECT, 0, 0, 0]),
+            Ok(Request::Connect {
+                selector: Selector::Any
+            })
+        );
+        assert_eq!(
+            parse_request(&[OP_RUMBLE, 255, 10, 0]),
+            Ok(Request::Rumble {
+                large: 255,
+                small: 10,
+                slot: 0
+            })
+        );
+        assert_eq!(
+            parse_request(&[OP_RUMBLE, 255, 10]),
+            Ok(Request::Rumble {
+                large: 255,
+                small: 10,
+                slot: 0
+            })
+        );
+        assert_eq!(parse_request(&[OP_RUMBLE, 255]), Err(ErrorCode::Malformed));
+        assert_eq!(parse_request(&[]), Err(ErrorCode::Malformed));
+    }
+
+    #[test]
+    fn parse_color() {
+        assert_eq!(
+            parse_request(&[OP_COLOR, 255, 0, 128, 2]),
+            Ok(Request::Color {
+                r: 255,
+                g: 0,
+                b: 128,
+                slot: 2
+            })
+        );
+        assert_eq!(
+            parse_request(&[OP_COLOR, 255, 0]),
+            Err(ErrorCode::Malformed)
+        );
+    }
+
+    #[test]
+    fn parse_trigger() {
+        assert_eq!(
+            parse_request(&[OP_TRIGGER, 1, 2, 3, 6, 8, 1]),
+            Ok(Request::TriggerEffect {
+                trigger: Trigger::Right,
+                effect: TriggerEffect::Weapon {
+                    start: 3,
+                    end: 6,
+                    strength: 8
+                },
+                slot: 1
+            })
+        );
+        // weapon end zone should be after start zone
+        assert_eq!(
+            parse_request(&[OP_TRIGGER, 0, 2, 6, 3, 8]),
+            Err(ErrorCode::Malformed)
+        );
+        assert_eq!(
+            parse_request(&[OP_TRIGGER, 2, 0, 0, 0, 0]),
+            Err(ErrorCode::Malformed)
+        );
+    }
+
+    #[test]
+    fn parse_leds() {
+        assert_eq!(
+            parse_request(&[OP_PLAYER_LEDS, 0, 3]),
+            Ok(Request::PlayerLeds {
+                leds: PlayerLeds::Battery,
+                slot: 3
+            })
+        );
+        assert_eq!(
+            parse_request(&[OP_PLAYER_LEDS, 5]),
+            Err(ErrorCode::Malformed)
+        );
+        assert_eq!(
+            parse_request(&[OP_MUTE_LED, 2]),
+            Ok(Request::MuteLed {
+                mute: MuteLed::Pulse,
+                slot: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parse_audio() {
+        assert_eq!(
+            parse_request(&[OP_AUDIO, 1, 0x9C, 0x79, 22]),
+            Ok(Request::Audio {
+                data: vec![0x9C, 0x79, 22],
+                slot: 1
+            })
+        );
+        assert_eq!(parse_request(&[OP_AUDIO, 1]), Err(ErrorCode::Malformed));
+        assert_eq!(
+            parse_request(&[OP_VOLUME, 40, 40, 60]),
+            Ok(Request::Volume {
+                left: 40,
+                right: 40,
+                speaker: 60,
+                slot: 0
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_eq!(parse_request(&[0x42]), Err(ErrorCode::UnknownOpcode));
+        assert_eq!(
+            encode_error(ErrorCode::UnknownOpcode, 0x42),
+            [OP_ERROR, 1, 0x42]
+        );
+    }
+
+    #[test]
+    fn encode_controllers() {
+        let gamepad = DSGamepad {
+            ds_type: DSType::SenseBT,
+            path: String::from("/dev/hidraw3"),
+            used_by: Some("127.0.0.1:9000".parse().unwrap()),
+            mac: None,
+            firmware: Some(0x0110),
+            backend: crate::udevmon::Backend::Hidraw,
+            corrupt_frames: Default::default(),
+            idle: None,
+        };
+        gamepad.corrupt_frames.store(3, Ordering::Relaxed);
+        let sysname = String::from("hidraw3");
+        let mut expected = vec![OP_LIST_REPLY, 1, 0, 1, 1, 7];
+        expected.extend_from_slice(b"hidraw3");
+        expected.push(14);
+        expected.extend_from_slice(b"127.0.0.1:9000");
+        expected.extend_from_slice(&[0, 0x10, 0x01, 0, 0]);
+        expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode_list(&[(&sysname, &gamepad)]), expected);
+
+        let gamepads = vec![(&sysname, &gamepad); 300];
+        let list = encode_list(&gamepads);
+        assert_eq!(list[1], 255);
+        assert_eq!(list.len(), 2 + 255 * (expected.len() - 2));
+        assert_eq!(list[list.len() - expected.len() + 2], 254);
+    }
+}
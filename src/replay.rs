@@ -0,0 +1,209 @@
+// Replayed gamepads: session recorded to capture file (see capture.rs) is served like
+// a live gamepad of the same type. Input reports are given to client with their
+// recorded timing, divided by speed, and when session ends, gamepad looks unplugged
+// or session starts again, if it is looped. Every client gets session from its start.
+// Output reports are dropped or appended to output file, like virtual gamepads do.
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::capture::{self, Direction, Session};
+use crate::config::ReplayConfig;
+use crate::hidraw::{DeviceReader, DeviceWriter, READ_TIMEOUT};
+use crate::udevmon::{Backend, DSGamepad, Gamepads};
+use crate::virtual_gamepad;
+
+fn load_session(replay: &ReplayConfig) -> io::Result<Session> {
+    let text = fs::read_to_string(&replay.capture)?;
+    let mut sessions = capture::parse_sessions(&text)?;
+    if replay.session > sessions.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "there are {} sessions, not {}",
+                sessions.len(),
+                replay.session
+            ),
+        ));
+    }
+    Ok(sessions.swap_remove(replay.session - 1))
+}
+
+struct ReplayReader {
+    // input reports and times to give them out
+    reports: Vec<(Duration, Vec<u8>)>,
+    next: usize,
+    start: Option<Instant>,
+    looped: bool,
+}
+
+impl ReplayReader {
+    // Speed is checked by Config::validate, so times could not overflow
+    fn new(session: &Session, speed: f32, looped: bool) -> Self {
+        let reports = session
+            .records
+            .iter()
+            .filter(|record| record.direction == Direction::Input)
+            .map(|record| (record.time.div_f32(speed), record.report.clone()))
+            .collect();
+        Self {
+            reports,
+            next: 0,
+            start: None,
+            looped,
+        }
+    }
+}
+
+impl Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.next == self.reports.len() {
+            if !self.looped || self.reports.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "end of replayed session",
+                ));
+            }
+            self.next = 0;
+            self.start = None;
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let (time, report) = &self.reports[self.next];
+        let now = Instant::now();
+        if start + *time > now {
+            // do not keep input thread, if there is long pause in session
+            let wait = start + *time - now;
+            thread::sleep(wait.min(READ_TIMEOUT));
+            if wait > READ_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no input report yet",
+                ));
+            }
+        }
+        self.next += 1;
+        let len = report.len().min(buf.len());
+        buf[..len].copy_from_slice(&report[..len]);
+        Ok(len)
+    }
+}
+
+pub fn open_gamepad(replay: &ReplayConfig, session: &Session) -> (DeviceReader, DeviceWriter) {
+    let reader = ReplayReader::new(session, replay.speed, replay.looped);
+    let writer: DeviceWriter = match &replay.output {
+        Some(output) => virtual_gamepad::report_writer(output),
+        None => Box::new(io::sink()),
+    };
+    (Box::new(reader), writer)
+}
+
+// Adds replayed gamepads from configuration to the list of gamepads,
+// type of gamepad is taken from capture file, which is parsed before list is locked
+pub fn register(gamepads: &Gamepads, replays: &[ReplayConfig]) {
+    for replay in replays {
+        let session = match load_session(replay) {
+            Ok(session) => session,
+            Err(e) => {
+                error!(
+                    controller = replay.name.as_str();
+                    "Could not load capture {}: {}", replay.capture.display(), e
+                );
+                continue;
+            }
+        };
+        info!(
+            controller = replay.name.as_str();
+            "Added replay of {:?} {}", session.ds_type, session.sysname
+        );
+        let gamepad = DSGamepad {
+            ds_type: session.ds_type,
+            path: replay.capture.display().to_string(),
+            used_by: None,
+            mac: replay.mac.as_ref().map(|mac| mac.to_lowercase()),
+            firmware: None,
+            backend: Backend::Replay {
+                replay: replay.clone(),
+                session: Arc::new(session),
+            },
+            corrupt_frames: Default::default(),
+            idle: None,
+        };
+        gamepads.write().insert(replay.name.clone(), gamepad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Record;
+    use crate::udevmon::DSType;
+
+    fn session() -> Session {
+        let record = |millis, direction, first| Record {
+            time: Duration::from_millis(millis),
+            direction,
+            report: vec![first, 0x00],
+        };
+        Session {
+            ds_type: DSType::DS4USB,
+            sysname: "hidraw3".to_string(),
+            records: vec![
+                record(0, Direction::Input, 0x01),
+                record(10, Direction::Output, 0x05),
+                record(40, Direction::Input, 0x02),
+            ],
+        }
+    }
+
+    fn read(reader: &mut ReplayReader) -> io::Result<u8> {
+        let mut buf = [0; 128];
+        let len = reader.read(&mut buf)?;
+        assert_eq!(len, 2);
+        Ok(buf[0])
+    }
+
+    #[test]
+    fn original_timing() {
+        let mut reader = ReplayReader::new(&session(), 1.0, false);
+        let start = Instant::now();
+        assert_eq!(read(&mut reader).unwrap(), 0x01);
+        assert_eq!(read(&mut reader).unwrap(), 0x02);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(read(&mut reader).is_err());
+    }
+
+    #[test]
+    fn faster_and_looped() {
+        let mut reader = ReplayReader::new(&session(), 4.0, true);
+        let start = Instant::now();
+        let firsts: Vec<u8> = (0..4).map(|_| read(&mut reader).unwrap()).collect();
+        assert_eq!(firsts, [0x01, 0x02, 0x01, 0x02]);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(20), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn long_pause_does_not_block() {
+        let mut session = session();
+        session.records[2].time = Duration::from_millis(250);
+        let mut reader = ReplayReader::new(&session, 1.0, false);
+        assert_eq!(read(&mut reader).unwrap(), 0x01);
+        let mut timeouts = 0;
+        let second = loop {
+            match read(&mut reader) {
+                Ok(first) => break first,
+                Err(e) => {
+                    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                    timeouts += 1;
+                }
+            }
+        };
+        assert_eq!(second, 0x02);
+        assert!(timeouts >= 1, "{}", timeouts);
+    }
+}
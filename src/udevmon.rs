@@ -1,19 +1,28 @@
 use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
+use crossbeam_channel::Sender;
+use log::{debug, error, info};
 use mio::{Events, Interest, Poll, Token};
 use parking_lot::RwLock;
 
+use crate::capture::Session;
+use crate::config::ReplayConfig;
+use crate::hidraw;
+
 const ID_DS4V2: &str = "000009CC";
 const ID_SENSE: &str = "00000CE6";
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DSType {
     DS4BT,
     DS4USB,
@@ -28,18 +37,61 @@ pub struct DSGamepad {
     pub ds_type: DSType,
     pub path: String,
     pub used_by: Option<SocketAddr>,
+    // stable identity of gamepad, same over USB and BT
+    pub mac: Option<String>,
+    pub firmware: Option<u32>,
+    pub backend: Backend,
+    // BT frames with bad CRC, which were dropped over all sessions of gamepad
+    pub corrupt_frames: Arc<AtomicU64>,
+    // output of gamepad, while it waits for a client
+    pub idle: Option<IdleOutput>,
+}
+
+// Thread, which keeps writing output reports to gamepad, which is not used by any client,
+// so animations, which gamepad could not do by itself, go on. It stops, when stop is dropped.
+#[derive(Debug)]
+pub struct IdleOutput {
+    pub stop: Sender<()>,
+    pub thread: JoinHandle<()>,
+}
+
+impl IdleOutput {
+    // Waits for thread, so its reports are not mixed with reports of next client
+    pub fn stop(self) {
+        drop(self.stop);
+        if self.thread.join().is_err() {
+            error!("Idle output thread panicked");
+        }
+    }
+}
+
+// Where reports of gamepad come from and go to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Hidraw,
+    Virtual {
+        input: PathBuf,
+        output: PathBuf,
+        interval: Duration,
+    },
+    // session is parsed from capture file once, when gamepad is added
+    Replay {
+        replay: ReplayConfig,
+        session: Arc<Session>,
+    },
 }
 
 fn handle_event(event: udev::Event, gamepads: &Gamepads) {
     let sysname = String::from(event.sysname().to_str().unwrap());
     if event.event_type() == udev::EventType::Add {
         if let Some(gamepad) = filter_gamepads(event.device()) {
+            info!(controller = sysname.as_str(); "Added {:?}", gamepad.ds_type);
             gamepads.write().insert(sysname, gamepad);
-            println!("Added {:?}", gamepads.read().keys());
         }
-    } else if event.event_type() == udev::EventType::Remove {
-        gamepads.write().remove(&sysname);
-        println!("Removed {:?}", gamepads.read().keys());
+    } else if event.event_type() == udev::EventType::Remove
+        && gamepads.write().remove(&sysname).is_some()
+    {
+        info!(controller = sysname.as_str(); "Removed");
     }
 }
 
@@ -63,7 +115,7 @@ fn poll(gamepads: Gamepads, global_stop: Arc<AtomicBool>) -> io::Result<()> {
         for event in &events {
             if event.token() == Token(0) && event.is_writable() {
                 socket.clone().for_each(|ev| handle_event(ev, &gamepads));
-                println!("{:?}", gamepads);
+                debug!("Gamepads after udev event {:?}", gamepads);
             }
         }
     }
@@ -93,21 +145,37 @@ fn filter_gamepads(device: udev::Device) -> Option<DSGamepad> {
             HashMap::from([(true, DSType::SenseBT), (false, DSType::SenseUSB)]),
         ),
     ]);
+    let ds_type = map.get(ids[2])?[&is_bt];
+    let (mut mac, firmware) = hidraw::read_identity(&path, ds_type);
+    if mac.is_none() {
+        // for BT devices, HID_UNIQ is MAC of gamepad
+        mac = parent
+            .property_value("HID_UNIQ")
+            .and_then(|uniq| uniq.to_str())
+            .filter(|uniq| !uniq.is_empty())
+            .map(|uniq| uniq.to_lowercase());
+    }
     Some(DSGamepad {
-        ds_type: map.get(ids[2])?[&is_bt],
+        ds_type,
         path,
         used_by: None,
+        mac,
+        firmware,
+        backend: Backend::Hidraw,
+        corrupt_frames: Default::default(),
+        idle: None,
     })
 }
 
-pub fn start_monitor(gamepads: &Gamepads, global_stop: Arc<AtomicBool>) {
-    let mut enumerator = udev::Enumerator::new().unwrap();
-    enumerator.match_subsystem("hidraw").unwrap();
-    for device in enumerator.scan_devices().unwrap() {
+// Fails, if udev is not available, e.g. in containers
+pub fn start_monitor(gamepads: &Gamepads, global_stop: Arc<AtomicBool>) -> io::Result<()> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("hidraw")?;
+    for device in enumerator.scan_devices()? {
         let sysname = String::from(device.sysname().to_str().unwrap());
         if let Some(gamepad) = filter_gamepads(device) {
+            info!(controller = sysname.as_str(); "Found {:?}", gamepad.ds_type);
             gamepads.write().insert(sysname, gamepad);
-            println!("Added {:?}", gamepads.read().keys());
         }
     }
     let gamepads = Arc::clone(gamepads);
@@ -115,7 +183,8 @@ pub fn start_monitor(gamepads: &Gamepads, global_stop: Arc<AtomicBool>) {
         .name(String::from("udev"))
         .spawn(move || poll(gamepads, global_stop))
     {
-        eprintln!("Error in creating thread for monitoring udev: {}", err);
+        error!("Error in creating thread for monitoring udev: {}", err);
         //stop.store(true, Ordering::SeqCst);
     }
+    Ok(())
 }
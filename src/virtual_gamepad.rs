@@ -0,0 +1,271 @@
+// Virtual gamepads, which are not hidraw devices, so server could be tested without hardware.
+// Input is a file or FIFO with raw input reports of gamepad type (64 bytes for USB, 78 for BT)
+// one after another, which are read not more often than once per interval. End of input
+// looks like unplugged gamepad to the client. Output reports are appended to output file
+// (which could be FIFO too), one report per line in hex:
+//   02 00 00 0F 55 ...
+// Files are opened by input and control threads, so FIFOs do not block the server.
+// Input is opened and read without blocking, so input thread stops in time, even if
+// nobody writes to FIFO. Reports should be written to FIFO whole, as part of report,
+// which is already read, is dropped, when client disconnects.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::capture::format_hex;
+use crate::common_input::{PACKET_LEN_BT, PACKET_LEN_USB};
+use crate::config::VirtualConfig;
+use crate::hidraw::{DeviceReader, DeviceWriter, READ_TIMEOUT};
+use crate::udevmon::{Backend, DSGamepad, DSType, Gamepads};
+
+fn report_len(ds_type: DSType) -> usize {
+    match ds_type {
+        DSType::DS4USB | DSType::SenseUSB => PACKET_LEN_USB,
+        DSType::DS4BT | DSType::SenseBT => PACKET_LEN_BT,
+    }
+}
+
+struct ReportReader {
+    path: PathBuf,
+    file: Option<File>,
+    report_len: usize,
+    interval: Duration,
+    last_read: Option<Instant>,
+    // beginning of report, which is not written whole yet
+    pending: Vec<u8>,
+}
+
+// Returns false, if there is nothing to read after timeout. End of input is readable.
+fn wait_readable(file: &File, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(res > 0)
+}
+
+impl Read for ReportReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // interval is counted between whole reports
+        if let Some(last_read) = self.last_read.filter(|_| self.pending.is_empty()) {
+            let next_read = last_read + self.interval;
+            let now = Instant::now();
+            if next_read > now {
+                thread::sleep(next_read - now);
+            }
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            // FIFO is opened even without writer, reads would wait for it
+            None => self.file.insert(
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&self.path)?,
+            ),
+        };
+        let len = self.report_len.min(buf.len());
+        while self.pending.len() < len {
+            if !wait_readable(file, READ_TIMEOUT)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no input report yet",
+                ));
+            }
+            let mut chunk = [0; PACKET_LEN_BT];
+            let missing = len - self.pending.len();
+            match file.read(&mut chunk[..missing]) {
+                // end of file, or FIFO has no writers any more
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input")),
+                Ok(count) => self.pending.extend_from_slice(&chunk[..count]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        buf[..len].copy_from_slice(&self.pending);
+        self.pending.clear();
+        self.last_read = Some(Instant::now());
+        Ok(len)
+    }
+}
+
+struct ReportWriter {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Write for ReportWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+        // whole line at once, so reader of output never sees half of report
+        file.write_all(format!("{}\n", format_hex(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Output reports are appended to file at path as hex lines
+pub fn report_writer(path: &Path) -> DeviceWriter {
+    Box::new(ReportWriter {
+        path: path.to_path_buf(),
+        file: None,
+    })
+}
+
+pub fn open_gamepad(
+    input: &Path,
+    output: &Path,
+    ds_type: DSType,
+    interval: Duration,
+) -> (DeviceReader, DeviceWriter) {
+    let reader = ReportReader {
+        path: input.to_path_buf(),
+        file: None,
+        report_len: report_len(ds_type),
+        interval,
+        last_read: None,
+        pending: Vec::new(),
+    };
+    (Box::new(reader), report_writer(output))
+}
+
+// Adds virtual gamepads from configuration to the list of gamepads
+pub fn register(gamepads: &Gamepads, configs: &[VirtualConfig]) {
+    let mut locked_gamepads = gamepads.write();
+    for config in configs {
+        let gamepad = DSGamepad {
+            ds_type: config.ds_type(),
+            path: config.input.display().to_string(),
+            used_by: None,
+            mac: config.mac.as_ref().map(|mac| mac.to_lowercase()),
+            firmware: None,
+            backend: Backend::Virtual {
+                input: config.input.clone(),
+                output: config.output.clone(),
+                interval: config.interval(),
+            },
+            corrupt_frames: Default::default(),
+            idle: None,
+        };
+        info!(controller = config.name.as_str(); "Added virtual {:?}", gamepad.ds_type);
+        locked_gamepads.insert(config.name.clone(), gamepad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::common_input::{Packet, PacketError};
+    use crate::hidraw;
+    use crate::input_ds4::DS4PacketUSB;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ds4net-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn reads_reports_until_end() {
+        let input = temp_path("reads.in");
+        let mut reports = vec![0u8; PACKET_LEN_USB * 2];
+        reports[0] = 0x01;
+        reports[PACKET_LEN_USB] = 0x01;
+        reports[PACKET_LEN_USB + 8] = 0xFF;
+        fs::write(&input, &reports).unwrap();
+        let (mut reader, _) = open_gamepad(
+            &input,
+            &temp_path("reads.out"),
+            DSType::DS4USB,
+            Duration::ZERO,
+        );
+        let mut packet = DS4PacketUSB::default();
+        hidraw::read_packet(&mut reader, &mut packet).unwrap();
+        assert_eq!(packet.decode().l2, 0);
+        hidraw::read_packet(&mut reader, &mut packet).unwrap();
+        assert_eq!(packet.decode().l2, 0xFF);
+        assert!(matches!(
+            hidraw::read_packet(&mut reader, &mut packet),
+            Err(PacketError::Io(_))
+        ));
+        fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn fifo_without_writer_does_not_block() {
+        let input = temp_path("fifo.in");
+        let path = CString::new(input.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let (mut reader, _) = open_gamepad(
+            &input,
+            &temp_path("fifo.out"),
+            DSType::DS4USB,
+            Duration::ZERO,
+        );
+        let mut buf = [0; 128];
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let mut writer = OpenOptions::new().write(true).open(&input).unwrap();
+        let mut report = [0u8; PACKET_LEN_USB];
+        report[0] = 0x01;
+        report[8] = 0xFF;
+        // report written in parts is read whole
+        writer.write_all(&report[..10]).unwrap();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        writer.write_all(&report[10..]).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), PACKET_LEN_USB);
+        assert_eq!(buf[..PACKET_LEN_USB], report);
+        drop(writer);
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn writes_reports_as_lines() {
+        let output = temp_path("writes.out");
+        let (_, mut writer) = open_gamepad(
+            &temp_path("writes.in"),
+            &output,
+            DSType::SenseBT,
+            Duration::ZERO,
+        );
+        hidraw::write_report(&mut writer, &[0x02, 0xFF, 0x10]).unwrap();
+        hidraw::write_report(&mut writer, &[0x05]).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "02 FF 10\n05\n");
+        fs::remove_file(&output).unwrap();
+    }
+}
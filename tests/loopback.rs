@@ -0,0 +1,679 @@
+// End-to-end tests: server is started with virtual gamepads on ephemeral port,
+// and talked to over loopback UDP like a real client would do.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// name of virtual gamepad, type in configuration
+const GAMEPADS: [(&str, &str); 4] = [
+    ("ds4usb", "ds4-usb"),
+    ("ds4bt", "ds4-bt"),
+    ("senseusb", "dualsense-usb"),
+    ("sensebt", "dualsense-bt"),
+];
+
+// DS4 BT report: sticks near center, d-pad neutral, battery 80%
+const DS4_REPORT_BT: [u8; 78] = [
+    0x11, 0xC0, 0x00, 0x7F, 0x80, 0x83, 0x7D, 0x08, 0x00, 0x54, 0x00, 0x00, 0x2C, 0xB5, 0x17, 0x03,
+    0x00, 0xFD, 0xFF, 0x01, 0x00, 0x6A, 0x00, 0x20, 0x20, 0x3A, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x08, 0x00, 0x00, 0x01, 0x4E, 0x80, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0B, 0xEE, 0xBF, 0x45,
+];
+
+// DualSense USB report: sticks moved, L2 half, R2 full, cross + R1 + R2 + PS + touchpad,
+// charging at 50% with headphones, one finger on touchpad
+const SENSE_REPORT_USB: [u8; 64] = [
+    0x01, 0x80, 0x7F, 0x81, 0x7E, 0x10, 0xFF, 0x2A, 0x28, 0x0A, 0x07, 0x00, 0x1B, 0x5A, 0x3C, 0xA1,
+    0xFE, 0xFF, 0x02, 0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x50, 0x34, 0x12, 0x00,
+    0x1A, 0x05, 0xC0, 0xC3, 0x21, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// the same state, received over BT
+const SENSE_REPORT_BT: [u8; 78] = [
+    0x31, 0x10, 0x80, 0x7F, 0x81, 0x7E, 0x10, 0xFF, 0x2A, 0x28, 0x0A, 0x07, 0x00, 0x1B, 0x5A, 0x3C,
+    0xA1, 0xFE, 0xFF, 0x02, 0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x50, 0x34, 0x12,
+    0x00, 0x1A, 0x05, 0xC0, 0xC3, 0x21, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x89, 0x42, 0xF1, 0x99,
+];
+
+// DS4 report, which clients get from both DualSense reports
+const SENSE_EXPECTED_DS4: [u8; 64] = [
+    0x00, 0x80, 0x7F, 0x81, 0x7E, 0x28, 0x0A, 0xAB, 0x10, 0xFF, 0x45, 0x23, 0x1A, 0xFE, 0xFF, 0x02,
+    0x00, 0x01, 0x00, 0x8C, 0x01, 0x52, 0x20, 0xC4, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x35, 0x00,
+    0x00, 0x01, 0x2A, 0x05, 0xC0, 0x73, 0x1D, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn ds4_report_usb() -> Vec<u8> {
+    let mut report = vec![0x01];
+    report.extend_from_slice(&DS4_REPORT_BT[3..66]);
+    report
+}
+
+// Input report, which virtual gamepad sends, and DS4 report, which client should get
+fn input_reports(sysname: &str) -> (Vec<u8>, Vec<u8>) {
+    match sysname {
+        "ds4usb" => (ds4_report_usb(), ds4_report_usb()),
+        "ds4bt" => (DS4_REPORT_BT.to_vec(), DS4_REPORT_BT[2..66].to_vec()),
+        "senseusb" => (SENSE_REPORT_USB.to_vec(), SENSE_EXPECTED_DS4.to_vec()),
+        _ => (SENSE_REPORT_BT.to_vec(), SENSE_EXPECTED_DS4.to_vec()),
+    }
+}
+
+fn bt_crc(report: &[u8]) -> [u8; 4] {
+    let mut digest = CRC.digest();
+    digest.update(&[0xA2]);
+    digest.update(&report[..report.len() - 4]);
+    digest.finalize().to_le_bytes()
+}
+
+// Output report after rumble (200, 100) and color (255, 128, 0) are set,
+// DualSense shows player 1, sequence number of DualSense BT report is not set
+fn expected_output(sysname: &str) -> Vec<u8> {
+    let ds4 = [100, 200, 255, 128, 0, 0, 0];
+    let ds4_volume = [0, 0, 0x49, 0, 0x85];
+    let mut sense = vec![0; 47];
+    sense[..4].copy_from_slice(&[0x0F, 0x55, 100, 200]);
+    sense[10] = 0x05;
+    sense[21] = 0x05;
+    sense[38] = 0x05;
+    sense[42..].copy_from_slice(&[0x02, 0x04, 255, 128, 0]);
+    let mut report = match sysname {
+        "ds4usb" => vec![0; 32],
+        "senseusb" => vec![0; 63],
+        _ => vec![0; 78],
+    };
+    match sysname {
+        "ds4usb" => {
+            report[..2].copy_from_slice(&[0x05, 0x07]);
+            report[4..11].copy_from_slice(&ds4);
+            report[19..24].copy_from_slice(&ds4_volume);
+        }
+        "ds4bt" => {
+            report[..4].copy_from_slice(&[0x11, 0xC4, 0x00, 0x07]);
+            report[6..13].copy_from_slice(&ds4);
+            report[21..26].copy_from_slice(&ds4_volume);
+        }
+        "senseusb" => {
+            report[0] = 0x02;
+            report[1..48].copy_from_slice(&sense);
+        }
+        _ => {
+            report[0] = 0x31;
+            report[2] = 0x10;
+            report[3..50].copy_from_slice(&sense);
+        }
+    }
+    report
+}
+
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(test: &str) -> Self {
+        Self::start_with(test, |_| String::new())
+    }
+
+    // None, if there is no IPv6 loopback, e.g. in containers
+    fn start_ipv6(test: &str) -> Option<Self> {
+        if UdpSocket::bind("[::1]:0").is_err() {
+            eprintln!("IPv6 loopback is not available, skipping {}", test);
+            return None;
+        }
+        Some(Self::launch(test, "::1", |_| String::new()))
+    }
+
+    // Extra configuration could refer to files, which it creates in directory of test
+    fn start_with(test: &str, extra_config: impl FnOnce(&Path) -> String) -> Self {
+        Self::launch(test, "127.0.0.1", extra_config)
+    }
+
+    fn launch(test: &str, listen: &str, extra_config: impl FnOnce(&Path) -> String) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("ds4net-loopback-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let extra = extra_config(&dir);
+        let mut config = format!("[server]\nlisten = \"{}\"\nport = 0\n", listen);
+        // connect flash would only slow tests down, unless test is about it
+        if !extra.contains("[lightbar]") {
+            config.push_str("[lightbar]\nconnect_flash_ms = 0\n");
+        }
+        for (sysname, ds_type) in GAMEPADS {
+            // about 10 seconds of reports
+            let report = input_reports(sysname).0;
+            fs::write(dir.join(format!("{}.in", sysname)), report.repeat(2500)).unwrap();
+            config.push_str(&format!(
+                "[[virtual]]\nname = \"{0}\"\ntype = \"{1}\"\ninput = \"{2}/{0}.in\"\noutput = \"{2}/{0}.out\"\n",
+                sysname,
+                ds_type,
+                dir.display()
+            ));
+        }
+        config.push_str(&extra);
+        let config_path = dir.join("ds4net.toml");
+        fs::write(&config_path, config).unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ds4net-rust"))
+            .arg("--config")
+            .arg(&config_path)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+        let addr = loop {
+            let line = lines
+                .next()
+                .expect("server exited before listening")
+                .unwrap();
+            if let Some((_, addr)) = line.split_once("Listening on ") {
+                break addr.trim().parse().unwrap();
+            }
+        };
+        // server should not block on full pipe
+        thread::spawn(move || lines.for_each(drop));
+        Self { child, addr, dir }
+    }
+
+    // Changes configuration file and tells server to reload it
+    fn reload(&self, edit: impl FnOnce(String) -> String) {
+        let path = self.dir.join("ds4net.toml");
+        let config = fs::read_to_string(&path).unwrap();
+        fs::write(&path, edit(config)).unwrap();
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGHUP) };
+    }
+
+    fn output_reports(&self, sysname: &str) -> Vec<Vec<u8>> {
+        let text =
+            fs::read_to_string(self.dir.join(format!("{}.out", sysname))).unwrap_or_default();
+        // last line could be written right now
+        text.split_inclusive('\n')
+            .filter(|line| line.ends_with('\n'))
+            .map(|line| {
+                line.split_whitespace()
+                    .map(|b| u8::from_str_radix(b, 16).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Client {
+    socket: UdpSocket,
+    // reports, which came while waiting for reply
+    reports: RefCell<VecDeque<Vec<u8>>>,
+}
+
+impl Client {
+    fn new(server: &Server) -> Self {
+        let local: SocketAddr = if server.addr.is_ipv6() {
+            "[::1]:0".parse().unwrap()
+        } else {
+            "127.0.0.1:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).unwrap();
+        socket.connect(server.addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        Self {
+            socket,
+            reports: RefCell::default(),
+        }
+    }
+
+    fn send(&self, buf: &[u8]) {
+        self.socket.send(buf).unwrap();
+    }
+
+    // Reports are kept for recv_report
+    fn recv_any_reply(&self) -> Vec<u8> {
+        let mut buf = [0; 1500];
+        loop {
+            let amt = self.socket.recv(&mut buf).unwrap();
+            if amt == 65 {
+                self.reports.borrow_mut().push_back(buf[..amt].to_vec());
+            } else {
+                return buf[..amt].to_vec();
+            }
+        }
+    }
+
+    // Skips replies, which do not start with opcode
+    fn recv_reply(&self, opcode: u8) -> Vec<u8> {
+        loop {
+            let reply = self.recv_any_reply();
+            if reply[0] == opcode {
+                return reply;
+            }
+        }
+    }
+
+    fn recv_report(&self) -> Vec<u8> {
+        if let Some(report) = self.reports.borrow_mut().pop_front() {
+            return report;
+        }
+        let mut buf = [0; 1500];
+        loop {
+            let amt = self.socket.recv(&mut buf).unwrap();
+            if amt == 65 {
+                return buf[..amt].to_vec();
+            }
+        }
+    }
+
+    // Hello with rumble and color outputs and selector, returns welcome
+    fn hello_with(&self, selector: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 1, 0x03, 4];
+        hello.extend_from_slice(b"test");
+        hello.extend_from_slice(selector);
+        self.send(&hello);
+        self.recv_reply(0x80)
+    }
+
+    // Hello with selector by sysname
+    fn hello(&self, sysname: &str) -> Vec<u8> {
+        let mut selector = vec![0x01, sysname.len() as u8];
+        selector.extend_from_slice(sysname.as_bytes());
+        self.hello_with(&selector)
+    }
+
+    // Owners of gamepads from controllers list
+    fn owners(&self) -> Vec<(String, String)> {
+        self.send(&[0x05]);
+        let reply = self.recv_reply(0x83);
+        let mut owners = Vec::new();
+        let mut rest = &reply[2..];
+        for _ in 0..reply[1] {
+            let (sysname, after) = rest[4..].split_at(rest[3] as usize);
+            let (owner, after) = after[1..].split_at(after[0] as usize);
+            let (_mac, after) = after[1..].split_at(after[0] as usize);
+            owners.push((
+                String::from_utf8(sysname.to_vec()).unwrap(),
+                String::from_utf8(owner.to_vec()).unwrap(),
+            ));
+            // firmware and corrupt frames
+            rest = &after[12..];
+        }
+        owners
+    }
+
+    fn owner_of(&self, sysname: &str) -> String {
+        self.owners()
+            .into_iter()
+            .find(|(name, _)| name == sysname)
+            .unwrap()
+            .1
+    }
+}
+
+#[test]
+fn input_reports_are_translated() {
+    let server = Server::start("input");
+    for (i, (sysname, _)) in GAMEPADS.into_iter().enumerate() {
+        let client = Client::new(&server);
+        let welcome = client.hello(sysname);
+        // controller type and connection type
+        let types = [(i / 2) as u8, (i % 2) as u8];
+        assert_eq!(
+            welcome[..5],
+            [0x80, 1, 0, types[0], types[1]],
+            "{}",
+            sysname
+        );
+        let report = client.recv_report();
+        assert_eq!(report[0], 0);
+        assert_eq!(report[1..], input_reports(sysname).1[..], "{}", sysname);
+    }
+}
+
+#[test]
+fn ipv6_client() {
+    let Some(server) = Server::start_ipv6("ipv6") else {
+        return;
+    };
+    assert!(server.addr.is_ipv6());
+    let client = Client::new(&server);
+    assert_eq!(client.hello("ds4bt")[..5], [0x80, 1, 0, 0, 1]);
+    assert_eq!(client.recv_report()[1..], input_reports("ds4bt").1[..]);
+    // owner is shown as IPv6 address
+    let addr = client.socket.local_addr().unwrap();
+    assert!(addr.to_string().starts_with("[::1]:"));
+    assert_eq!(client.owner_of("ds4bt"), addr.to_string());
+}
+
+#[test]
+fn output_reports_are_written() {
+    let server = Server::start("output");
+    for (sysname, _) in GAMEPADS {
+        let client = Client::new(&server);
+        client.hello(sysname);
+        client.send(&[0x01, 200, 100, 0]);
+        client.send(&[0x08, 255, 128, 0, 0]);
+        let expected = expected_output(sysname);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let reports = loop {
+            let reports = server.output_reports(sysname);
+            let found = reports.iter().any(|report| match sysname {
+                "sensebt" => report[0] == 0x31 && report[2..74] == expected[2..74],
+                "ds4bt" => report[..74] == expected[..74],
+                _ => *report == expected,
+            });
+            if found {
+                break reports;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "{}: no expected report in {:02X?}",
+                sysname,
+                reports
+            );
+            thread::sleep(Duration::from_millis(20));
+        };
+        for (i, report) in reports.iter().enumerate() {
+            if sysname.ends_with("bt") {
+                assert_eq!(report[74..], bt_crc(report), "{} report {}", sysname, i);
+            }
+            // DualSense counts its BT reports
+            if sysname == "sensebt" {
+                assert_eq!(report[1], ((i % 16) << 4) as u8, "report {}", i);
+            }
+        }
+    }
+}
+
+#[test]
+fn outputs_are_negotiated() {
+    let server = Server::start("outputs");
+    let client = Client::new(&server);
+    // rumble and color are requested, flash and player LEDs are not
+    assert_eq!(client.hello("senseusb")[5], 0x03);
+    client.send(&[0x09, 20, 20, 0]);
+    assert_eq!(client.recv_reply(0x81), [0x81, 7, 0x09]);
+    client.send(&[0x0B, 2, 0]);
+    assert_eq!(client.recv_reply(0x81), [0x81, 7, 0x0B]);
+    // and DualSense has no volume at all
+    client.send(&[0x0E, 40, 40, 60, 0]);
+    assert_eq!(client.recv_reply(0x81), [0x81, 7, 0x0E]);
+}
+
+#[test]
+fn connect_flash_does_not_hold_up_requests() {
+    let server = Server::start_with("connect", |_| {
+        String::from(
+            "[lightbar]\nidle_color = [0, 0, 255]\nconnect_color = [0, 255, 0]\nconnect_flash_ms = 500\n",
+        )
+    });
+    let client = Client::new(&server);
+    let start = Instant::now();
+    client.hello("ds4usb");
+    let mut attach = vec![0x06, 0x01, 5];
+    attach.extend_from_slice(b"ds4bt");
+    client.send(&attach);
+    assert_eq!(client.recv_reply(0x80)[..3], [0x80, 1, 1]);
+    assert!(start.elapsed() < Duration::from_millis(400));
+    // lightbar shows connect color, then goes back to idle color
+    let colors = |sysname| -> Vec<Vec<u8>> {
+        let reports = server.output_reports(sysname);
+        let offset = if sysname == "ds4usb" { 6 } else { 8 };
+        reports
+            .iter()
+            .map(|report| report[offset..offset + 3].to_vec())
+            .collect()
+    };
+    let deadline = Instant::now() + Duration::from_secs(3);
+    for sysname in ["ds4usb", "ds4bt"] {
+        while colors(sysname).last() != Some(&vec![0, 0, 255]) {
+            assert!(Instant::now() < deadline, "{} shows no idle color", sysname);
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(colors(sysname).contains(&vec![0, 255, 0]), "{}", sysname);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn disconnect_releases_gamepad() {
+    let server = Server::start("disconnect");
+    let client = Client::new(&server);
+    client.hello("senseusb");
+    let addr = client.socket.local_addr().unwrap().to_string();
+    assert_eq!(client.owner_of("senseusb"), addr);
+    assert_eq!(client.owner_of("ds4usb"), "");
+    client.send(&[0x01, 200, 100, 0]);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !server
+        .output_reports("senseusb")
+        .iter()
+        .any(|report| report[3..5] == [100, 200])
+    {
+        assert!(Instant::now() < deadline, "gamepad does not rumble");
+        thread::sleep(Duration::from_millis(20));
+    }
+    client.send(&[0x02]);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !client.owner_of("senseusb").is_empty() {
+        assert!(Instant::now() < deadline, "gamepad is still used");
+        thread::sleep(Duration::from_millis(20));
+    }
+    // gamepad is released only after it is told to stop rumbling
+    let reports = server.output_reports("senseusb");
+    assert_eq!(reports.last().unwrap()[3..5], [0, 0]);
+    // and it could be taken by another client
+    let other = Client::new(&server);
+    assert_eq!(other.hello("senseusb")[..3], [0x80, 1, 0]);
+    assert_eq!(
+        other.owner_of("senseusb"),
+        other.socket.local_addr().unwrap().to_string()
+    );
+}
+
+#[test]
+fn crashed_legacy_client_is_reaped() {
+    let server = Server::start("legacy");
+    let legacy = Client::new(&server);
+    let mut connect = vec![0x00, 0x01, 6];
+    connect.extend_from_slice(b"ds4usb");
+    legacy.send(&connect);
+    let other = Client::new(&server);
+    let addr = legacy.socket.local_addr().unwrap().to_string();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while other.owner_of("ds4usb") != addr {
+        assert!(Instant::now() < deadline, "gamepad is not used");
+        thread::sleep(Duration::from_millis(20));
+    }
+    legacy.send(&[0x04]);
+    legacy.recv_reply(0x82);
+    // client crashes after its keepalive and sends nothing more
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !other.owner_of("ds4usb").is_empty() {
+        assert!(Instant::now() < deadline, "gamepad is still used");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn reload_disconnects_what_is_not_allowed() {
+    let server = Server::start("reload");
+    let client = Client::new(&server);
+    client.hello("ds4usb");
+    let mut attach = vec![0x06, 0x01, 8];
+    attach.extend_from_slice(b"senseusb");
+    client.send(&attach);
+    assert_eq!(client.recv_reply(0x80)[..3], [0x80, 1, 1]);
+    // DualSense is not allowed anymore, so its slot is closed, DS4 one is kept
+    server.reload(|config| config + "[controller]\nallowed_types = [\"ds4-usb\"]\n");
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while !client.owner_of("senseusb").is_empty() {
+        assert!(Instant::now() < deadline, "DualSense is still used");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let addr = client.socket.local_addr().unwrap().to_string();
+    assert_eq!(client.owner_of("ds4usb"), addr);
+    // then client itself is not allowed, its requests are rejected and session is closed
+    server.reload(|config| {
+        config.replace(
+            "[server]\n",
+            "[server]\nallowed_clients = [\"10.0.0.0/8\"]\n",
+        )
+    });
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        client.send(&[0x05]);
+        let reply = client.recv_any_reply();
+        if reply[0] == 0x81 {
+            assert_eq!(reply, [0x81, 8, 0x05]);
+            break;
+        }
+        assert!(Instant::now() < deadline, "client is still allowed");
+        thread::sleep(Duration::from_millis(50));
+    }
+    // reports, which were sent before session was closed, are dropped
+    thread::sleep(Duration::from_millis(200));
+    client
+        .socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut buf = [0; 1500];
+    let start = Instant::now();
+    while let Ok(amt) = client.socket.recv(&mut buf) {
+        assert!(
+            start.elapsed() < Duration::from_millis(100),
+            "client still gets {} bytes",
+            amt
+        );
+    }
+}
+
+#[test]
+fn waiting_flash_goes_on_without_client() {
+    let server = Server::start("waiting");
+    let client = Client::new(&server);
+    client.hello("senseusb");
+    client.send(&[0x02]);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !client.owner_of("senseusb").is_empty() {
+        assert!(Instant::now() < deadline, "gamepad is still used");
+        thread::sleep(Duration::from_millis(20));
+    }
+    let released = server.output_reports("senseusb").len();
+    // DualSense could not flash by itself, so lightbar is switched off and on by server
+    let deadline = Instant::now() + Duration::from_secs(4);
+    loop {
+        let colors: Vec<Vec<u8>> = server.output_reports("senseusb")[released..]
+            .iter()
+            .map(|report| report[45..48].to_vec())
+            .collect();
+        let dark = colors.iter().position(|color| *color == [0, 0, 0]);
+        if dark.is_some_and(|dark| colors[dark..].contains(&vec![0, 0, 255])) {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "lightbar does not flash: {:?}",
+            colors
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn gamepad_is_remembered_over_reconnect() {
+    let server = Server::start_with("remember", |dir| {
+        let mut config = String::new();
+        for (i, name) in ["pad0", "pad1"].into_iter().enumerate() {
+            fs::write(
+                dir.join(format!("{}.in", name)),
+                ds4_report_usb().repeat(2500),
+            )
+            .unwrap();
+            config.push_str(&format!(
+                "[[virtual]]\nname = \"{0}\"\ntype = \"ds4-usb\"\ninput = \"{1}/{0}.in\"\noutput = \"{1}/{0}.out\"\nmac = \"00:11:22:33:44:5{2}\"\n",
+                name,
+                dir.display(),
+                i
+            ));
+        }
+        config
+    });
+    let client = Client::new(&server);
+    client.hello("pad1");
+    client.send(&[0x02]);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !client.owner_of("pad1").is_empty() {
+        assert!(Instant::now() < deadline, "gamepad is still used");
+        thread::sleep(Duration::from_millis(20));
+    }
+    // restarted client comes from another port and does not care, which gamepad it gets
+    let restarted = Client::new(&server);
+    assert_ne!(
+        restarted.socket.local_addr().unwrap(),
+        client.socket.local_addr().unwrap()
+    );
+    restarted.hello_with(&[]);
+    assert_eq!(
+        restarted.owner_of("pad1"),
+        restarted.socket.local_addr().unwrap().to_string()
+    );
+}
+
+#[test]
+fn capture_is_replayed() {
+    let server = Server::start_with("replay", |dir| {
+        // three DS4 BT reports with different left stick, recorded 20ms apart
+        let mut capture = String::from("# ds4net capture 1 ds4-bt hidraw3\n");
+        for i in 0..3u8 {
+            let mut report = DS4_REPORT_BT;
+            report[3] = i;
+            let mut digest = CRC.digest();
+            digest.update(&[0xA1]);
+            digest.update(&report[..74]);
+            report[74..].copy_from_slice(&digest.finalize().to_le_bytes());
+            let hex: Vec<String> = report.iter().map(|b| format!("{:02X}", b)).collect();
+            capture.push_str(&format!("{} I {}\n", i as u32 * 20000, hex.join(" ")));
+            capture.push_str(&format!("{} O 11 C4\n", i as u32 * 20000 + 500));
+        }
+        fs::write(dir.join("menu.capture"), capture).unwrap();
+        format!(
+            "[[replay]]\nname = \"replay0\"\ncapture = \"{}/menu.capture\"\nspeed = 2.0\n",
+            dir.display()
+        )
+    });
+    let client = Client::new(&server);
+    assert_eq!(client.hello("replay0")[..5], [0x80, 1, 0, 0, 1]);
+    let start = Instant::now();
+    let sticks: Vec<u8> = (0..3).map(|_| client.recv_report()[2]).collect();
+    assert_eq!(sticks, [0, 1, 2]);
+    // 40ms of session are replayed twice as fast
+    assert!(start.elapsed() >= Duration::from_millis(15));
+    // after the end of session, gamepad looks unplugged and is free again
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !client.owner_of("replay0").is_empty() {
+        assert!(Instant::now() < deadline, "replayed gamepad is still used");
+        thread::sleep(Duration::from_millis(20));
+    }
+}